@@ -1,68 +1,169 @@
-import os
 import sys
 import struct
-import subprocess
-from ast import literal_eval
 from os import listdir
-from os.path import isfile, join
+from os.path import isdir, isfile, join
+
+# Builds a disk image for the kernel's InodeFS (see my_kernel/src/fs/mod.rs)
+#
+#   | superblock | free bitmap | inode table | data blocks ... |
+
+BLOCK_SIZE = 4096
+FS_MAGIC = 0x34127788
+FS_VERSION = 1
+
+INODE_SIZE = 64
+INODES_PER_BLOCK = BLOCK_SIZE // INODE_SIZE
+DIRECT_BLOCKS = 10
+PTRS_PER_BLOCK = BLOCK_SIZE // 4
+MAX_FILE_BLOCKS = DIRECT_BLOCKS + PTRS_PER_BLOCK
+
+DIR_ENTRY_SIZE = 64
+MAX_NAME_LEN = 56
+
+ROOT_INODE = 1
+KIND_FILE = 1
+KIND_DIR = 2
+
+# at least this many inodes, more if the tree needs them
+MIN_INODE_COUNT = 1024
+# room left for files created at runtime
+FREE_BLOCKS = 2048
+
+
+def div_round_up(number, multiple):
+    return (number + multiple - 1) // multiple
+
+
+def fail(message):
+    print("filesystem_gen: " + message, file=sys.stderr)
+    sys.exit(1)
 
-def round_to_multiple(number, multiple):
-    return multiple * round((number + (multiple / 2.0)) / multiple)
 
 args = sys.argv
+if len(args) != 3:
+    fail("usage: filesystem_gen.py <image> <directory>")
 
 fs_image_name = args[1]
 dir = args[2]
 
-files = [f for f in listdir(dir) if isfile(join(dir, f))]
-num_files = len(files)
-num_files_bytes = struct.pack('<I', num_files)
+# number of data blocks each file needs, including its indirect block
+def blocks_for(size):
+    blocks = div_round_up(size, BLOCK_SIZE)
+    return blocks + (1 if blocks > DIRECT_BLOCKS else 0)
 
-trailing_padding = []
 
-sector_size = 512
-padding_after_string = 0
-with open(fs_image_name, "wb") as f:
-    magic = b'\x77\x77\x12\x34' # header magic
-    f.write(magic)
-    sector_size -= len(magic)
-    padding_after_string += len(magic)
-
-    f.write(num_files_bytes)
-    sector_size -= len(num_files_bytes)
-    padding_after_string += len(num_files_bytes)
-
-    for file in files:
-        s = file.encode() + b'\x00'
-        f.write(s)
-        sector_size -= len(s)
-        padding_after_string += len(s)
-
-    pad = round_to_multiple(padding_after_string, 8) - padding_after_string
-
-    f.write(b'\x00'*pad)
-    sector_size -= pad
-
-    offset = 512
-    for file in files:
-        file_size = os.path.getsize(join(dir, file))
-        file_size_bytes = struct.pack('<Q', file_size)
-        offset_bytes = struct.pack('<Q', offset)
-        f.write(offset_bytes)
-        sector_size -= len(offset_bytes)
-        f.write(file_size_bytes)
-        sector_size -= len(file_size_bytes)
-        rounded = round_to_multiple(offset+file_size, 512)
-        padding = rounded - (offset+file_size)
-        offset = rounded
-        trailing_padding.append(padding)
-
-    f.write(b'\x00'*sector_size)
-
-    i = 0
-    for file in files:
-        with open(join(dir, file), "rb") as prog:
-            f.write(prog.read())
-        f.write(b'\x00'*trailing_padding[i])
-        i += 1
+def dir_entry(ino, kind, name):
+    name = name.encode()
+    if len(name) > MAX_NAME_LEN or b'\x00' in name:
+        fail("invalid name %r, at most %d bytes are allowed" % (name, MAX_NAME_LEN))
+    return struct.pack('<IBBH', ino, kind, len(name), 0) + name + b'\x00' * (MAX_NAME_LEN - len(name))
+
+
+# inode number -> (kind, contents), directories hold their serialized entries
+inodes = {}
+next_ino = ROOT_INODE + 1
+
+
+def walk(path, ino, parent):
+    global next_ino
+    entries = dir_entry(ino, KIND_DIR, '.') + dir_entry(parent, KIND_DIR, '..')
+    for name in sorted(listdir(path)):
+        full = join(path, name)
+        child = next_ino
+        next_ino += 1
+        if isdir(full):
+            entries += dir_entry(child, KIND_DIR, name)
+            walk(full, child, ino)
+        elif isfile(full):
+            entries += dir_entry(child, KIND_FILE, name)
+            with open(full, "rb") as f:
+                data = f.read()
+            if blocks_for(len(data)) > MAX_FILE_BLOCKS + 1:
+                fail("%s is too large, the limit is %d bytes" % (full, MAX_FILE_BLOCKS * BLOCK_SIZE))
+            inodes[child] = (KIND_FILE, data)
+        else:
+            fail("%s is not a regular file or directory" % full)
+    if blocks_for(len(entries)) > MAX_FILE_BLOCKS + 1:
+        fail("%s has too many entries" % path)
+    inodes[ino] = (KIND_DIR, entries)
+
+
+walk(dir, ROOT_INODE, ROOT_INODE)
 
+data_blocks = sum(blocks_for(len(data)) for _, data in inodes.values())
+
+# inode 0 is never used
+inode_count = max(MIN_INODE_COUNT, next_ino)
+inode_table_blocks = div_round_up(inode_count, INODES_PER_BLOCK)
+bitmap_blocks = 1
+while True:
+    data_start = 1 + bitmap_blocks + inode_table_blocks
+    total_blocks = data_start + data_blocks + FREE_BLOCKS
+    if bitmap_blocks * BLOCK_SIZE * 8 >= total_blocks:
+        break
+    bitmap_blocks += 1
+
+# block numbers are 32 bit on disk
+if total_blocks >= 1 << 32:
+    fail("image would need %d blocks" % total_blocks)
+
+image = bytearray(total_blocks * BLOCK_SIZE)
+next_block = data_start
+
+
+def alloc_block():
+    global next_block
+    block = next_block
+    next_block += 1
+    image[1 * BLOCK_SIZE + block // 8] |= 1 << (block % 8)
+    return block
+
+
+def write_inode(ino, kind, data):
+    blocks = []
+    for off in range(0, len(data), BLOCK_SIZE):
+        block = alloc_block()
+        chunk = data[off:off + BLOCK_SIZE]
+        image[block * BLOCK_SIZE:block * BLOCK_SIZE + len(chunk)] = chunk
+        blocks.append(block)
+
+    direct = blocks[:DIRECT_BLOCKS] + [0] * (DIRECT_BLOCKS - len(blocks[:DIRECT_BLOCKS]))
+    indirect = 0
+    if len(blocks) > DIRECT_BLOCKS:
+        indirect = alloc_block()
+        rest = blocks[DIRECT_BLOCKS:]
+        table = struct.pack('<%dI' % len(rest), *rest)
+        image[indirect * BLOCK_SIZE:indirect * BLOCK_SIZE + len(table)] = table
+
+    raw = struct.pack('<HHIQ', kind, 1, 0, len(data))
+    raw += struct.pack('<%dI' % DIRECT_BLOCKS, *direct)
+    raw += struct.pack('<II', indirect, 0)
+    assert len(raw) == INODE_SIZE
+
+    offset = (1 + bitmap_blocks) * BLOCK_SIZE + ino * INODE_SIZE
+    image[offset:offset + INODE_SIZE] = raw
+
+
+for ino, (kind, data) in sorted(inodes.items()):
+    write_inode(ino, kind, data)
+
+# the metadata blocks are in use too
+for block in range(data_start):
+    image[1 * BLOCK_SIZE + block // 8] |= 1 << (block % 8)
+
+image[0:0x28] = struct.pack(
+    '<10I',
+    FS_MAGIC,
+    FS_VERSION,
+    BLOCK_SIZE,
+    total_blocks,
+    inode_count,
+    1,  # bitmap start
+    bitmap_blocks,
+    1 + bitmap_blocks,  # inode table start
+    inode_table_blocks,
+    data_start,
+)
+
+with open(fs_image_name, "wb") as f:
+    f.write(image)
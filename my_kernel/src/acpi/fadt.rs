@@ -0,0 +1,122 @@
+use super::{read_u16, read_u32, read_u64, read_u8, Sdt};
+
+// offsets into the table, header included
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const PM1A_CONTROL: usize = 64;
+const PM1B_CONTROL: usize = 68;
+const PM1_CONTROL_LEN: usize = 89;
+const CENTURY: usize = 108;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_CONTROL: usize = 172;
+const X_PM1B_CONTROL: usize = 184;
+
+const RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    Other(u8),
+}
+
+// A register given by the firmware, either memory mapped or an I/O port
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    // None if the table is too short for it or it is left zero
+    fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        let space = match read_u8(bytes, offset)? {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            other => AddressSpace::Other(other),
+        };
+        let address = read_u64(bytes, offset + 4)?;
+        if address == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            space,
+            bit_width: read_u8(bytes, offset + 1)?,
+            bit_offset: read_u8(bytes, offset + 2)?,
+            access_size: read_u8(bytes, offset + 3)?,
+            address,
+        })
+    }
+
+    // the ACPI 1.0 fields are plain I/O ports
+    fn io_port(port: u32, len: u8) -> Option<GenericAddress> {
+        if port == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            space: AddressSpace::Io,
+            bit_width: len * 8,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        })
+    }
+}
+
+// The parts of the fixed ACPI description table that power management uses
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub dsdt: usize,
+    pub sci_interrupt: u16,
+    // port to write `acpi_enable` to for ACPI mode, 0 if it is always on
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: Option<GenericAddress>,
+    pub pm1b_control: Option<GenericAddress>,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    // CMOS register of the RTC century
+    pub century: Option<u8>,
+}
+
+impl Fadt {
+    pub fn parse(sdt: &Sdt) -> Option<Fadt> {
+        let bytes = sdt.bytes();
+        let pm1_control_len = read_u8(bytes, PM1_CONTROL_LEN)?;
+        // the 64 bit fields win where the firmware fills them in
+        let dsdt = match read_u64(bytes, X_DSDT) {
+            Some(dsdt) if dsdt != 0 => dsdt as usize,
+            _ => read_u32(bytes, DSDT)? as usize,
+        };
+        let pm1a_control = GenericAddress::parse(bytes, X_PM1A_CONTROL)
+            .or_else(|| GenericAddress::io_port(read_u32(bytes, PM1A_CONTROL)?, pm1_control_len));
+        let pm1b_control = GenericAddress::parse(bytes, X_PM1B_CONTROL)
+            .or_else(|| GenericAddress::io_port(read_u32(bytes, PM1B_CONTROL)?, pm1_control_len));
+        let reset_register = match read_u32(bytes, FLAGS) {
+            Some(flags) if flags & RESET_REG_SUP != 0 => {
+                GenericAddress::parse(bytes, RESET_REGISTER)
+            }
+            _ => None,
+        };
+
+        Some(Fadt {
+            dsdt,
+            sci_interrupt: read_u16(bytes, SCI_INTERRUPT)?,
+            smi_command: read_u32(bytes, SMI_COMMAND)?,
+            acpi_enable: read_u8(bytes, ACPI_ENABLE)?,
+            pm1a_control,
+            pm1b_control,
+            reset_register,
+            reset_value: read_u8(bytes, RESET_VALUE).unwrap_or(0),
+            century: read_u8(bytes, CENTURY).filter(|&century| century != 0),
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use alloc::vec::Vec;
+
+use super::{read_u16, read_u32, read_u64, read_u8, Sdt};
+use crate::apic::ioapic::{InterruptSourceOverride, Polarity, TriggerMode};
+
+// entry types
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDRESS: u8 = 5;
+
+// set when the 8259 PICs are there as well
+const PCAT_COMPAT: u32 = 1 << 0;
+
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+// a disabled cpu with this set can still be brought up
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// overrides are only given for the ISA bus
+const BUS_ISA: u8 = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: usize,
+    pub gsi_base: u32,
+}
+
+// The interrupt controllers of the machine
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_base: u64,
+    pub legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub ioapics: Vec<MadtIoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+impl Madt {
+    pub fn parse(sdt: &Sdt) -> Option<Madt> {
+        let data = sdt.data();
+        let mut madt = Madt {
+            local_apic_base: read_u32(data, 0)? as u64,
+            legacy_pics: read_u32(data, 4)? & PCAT_COMPAT != 0,
+            local_apics: Vec::new(),
+            ioapics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = 8;
+        while offset + 2 <= data.len() {
+            let len = data[offset + 1] as usize;
+            if len < 2 || offset + len > data.len() {
+                break;
+            }
+            // entries too short for their type are skipped
+            madt.parse_entry(data[offset], &data[offset..offset + len]);
+            offset += len;
+        }
+        Some(madt)
+    }
+
+    fn parse_entry(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            LOCAL_APIC => {
+                let flags = read_u32(entry, 4)?;
+                self.local_apics.push(LocalApic {
+                    processor_id: read_u8(entry, 2)?,
+                    apic_id: read_u8(entry, 3)?,
+                    enabled: flags & LOCAL_APIC_ENABLED != 0,
+                    online_capable: flags & LOCAL_APIC_ONLINE_CAPABLE != 0,
+                });
+            }
+            IO_APIC => self.ioapics.push(MadtIoApic {
+                id: read_u8(entry, 2)?,
+                address: read_u32(entry, 4)? as usize,
+                gsi_base: read_u32(entry, 8)?,
+            }),
+            SOURCE_OVERRIDE => {
+                if read_u8(entry, 2)? != BUS_ISA {
+                    return None;
+                }
+                let flags = read_u16(entry, 8)?;
+                self.overrides.push(InterruptSourceOverride {
+                    irq: read_u8(entry, 3)?,
+                    gsi: read_u32(entry, 4)?,
+                    polarity: polarity(flags),
+                    trigger: trigger_mode(flags),
+                });
+            }
+            LOCAL_APIC_ADDRESS => self.local_apic_base = read_u64(entry, 4)?,
+            _ => {}
+        }
+        Some(())
+    }
+
+    // Local APICs of cpus that are running or can be started
+    pub fn usable_cpus(&self) -> impl Iterator<Item = &LocalApic> {
+        self.local_apics
+            .iter()
+            .filter(|apic| apic.enabled || apic.online_capable)
+    }
+}
+
+// 0b00 means whatever the bus uses, which is active high for ISA
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    }
+}
+
+// 0b00 means whatever the bus uses, which is edge for ISA
+fn trigger_mode(flags: u16) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    }
+}
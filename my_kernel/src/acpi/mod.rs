@@ -0,0 +1,243 @@
+pub mod fadt;
+pub mod madt;
+pub mod power;
+
+use alloc::vec::Vec;
+use core::{ptr, slice};
+
+use crate::memory::page_table::{PageFlags, PML4};
+use crate::memory::phys_map::phys_to_virt;
+use crate::println;
+use fadt::{AddressSpace, Fadt};
+use madt::Madt;
+
+// the BIOS keeps the real mode segment of the EBDA here
+const EBDA_SEGMENT_PTR: usize = 0x40e;
+// the RSDP is in the first KiB of the EBDA or in this range
+const EBDA_SEARCH_SIZE: usize = 0x400;
+const BIOS_AREA_START: usize = 0xe0000;
+const BIOS_AREA_END: usize = 0x100000;
+
+const RSDP_SIGNATURE: &[u8] = b"RSD PTR ";
+// the ACPI 1.0 part of the RSDP, covered by the first checksum
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+const SDT_HEADER_SIZE: usize = 36;
+
+// A system description table whose checksum is good
+#[derive(Debug, Clone, Copy)]
+pub struct Sdt {
+    pub paddr: usize,
+    bytes: &'static [u8],
+}
+
+impl Sdt {
+    /// Maps the table at `paddr` if it is outside the physical memory map
+    /// and checks it. Returns None if it is not a valid table.
+    /// # Safety
+    /// `paddr` must not be memory the kernel or a process uses
+    unsafe fn load(paddr: usize, pml4: &mut PML4) -> Option<Sdt> {
+        let header = map_phys(paddr, SDT_HEADER_SIZE, PageFlags::NO_EXECUTE, pml4);
+        let len = read_u32(header, 4)? as usize;
+        if len < SDT_HEADER_SIZE {
+            return None;
+        }
+        let sdt = Sdt {
+            paddr,
+            bytes: map_phys(paddr, len, PageFlags::NO_EXECUTE, pml4),
+        };
+        if !checksum_ok(sdt.bytes) {
+            println!(
+                "ACPI: bad checksum on {} at {:#x}",
+                sdt.signature_str(),
+                paddr
+            );
+            return None;
+        }
+        Some(sdt)
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        &self.bytes[0..4]
+    }
+
+    pub fn signature_str(&self) -> &str {
+        core::str::from_utf8(self.signature()).unwrap_or("????")
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    // the whole table, header included
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    // everything after the header
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[SDT_HEADER_SIZE..]
+    }
+}
+
+// What the firmware told us about the machine
+pub struct Acpi {
+    // every table found, including ones nothing parses yet
+    pub tables: Vec<Sdt>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+}
+
+static ACPI: spin::Mutex<Option<Acpi>> = spin::Mutex::new(None);
+
+/// Finds the ACPI tables and parses the ones the kernel needs.
+/// Returns false if there is no valid RSDP.
+/// # Safety
+/// Physical memory must be mapped and `pml4` must be the active page table
+pub unsafe fn init_acpi(pml4: &mut PML4) -> bool {
+    let (root, entry_size) = match find_rsdp() {
+        Some(root) => root,
+        None => {
+            println!("ACPI: no RSDP found");
+            return false;
+        }
+    };
+    let root = match Sdt::load(root, pml4) {
+        Some(root) => root,
+        None => return false,
+    };
+
+    let mut tables = Vec::new();
+    for entry in root.data().chunks_exact(entry_size) {
+        let paddr = match entry_size {
+            8 => read_u64(entry, 0).unwrap() as usize,
+            _ => read_u32(entry, 0).unwrap() as usize,
+        };
+        if let Some(sdt) = Sdt::load(paddr, pml4) {
+            tables.push(sdt);
+        }
+    }
+
+    let find = |signature: &[u8]| tables.iter().find(|sdt| sdt.signature() == signature);
+    let madt = find(b"APIC").and_then(Madt::parse);
+    let fadt = find(b"FACP").and_then(Fadt::parse);
+    // the DSDT is only reachable through the FADT
+    if let Some(dsdt) = fadt.and_then(|fadt| Sdt::load(fadt.dsdt, pml4)) {
+        tables.push(dsdt);
+    }
+    // registers `power` writes to, if they are memory mapped
+    if let Some(fadt) = &fadt {
+        let registers = [fadt.pm1a_control, fadt.pm1b_control, fadt.reset_register];
+        for reg in registers.iter().flatten() {
+            if reg.space == AddressSpace::Memory {
+                map_phys(
+                    reg.address as usize,
+                    4,
+                    PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::UNCACHED,
+                    pml4,
+                );
+            }
+        }
+    }
+
+    let names: Vec<&str> = tables.iter().map(|sdt| sdt.signature_str()).collect();
+    println!("ACPI tables: {:?}", names);
+    if let Some(madt) = &madt {
+        println!(
+            "ACPI: {} cpus, {} I/O APICs, {} overrides",
+            madt.usable_cpus().count(),
+            madt.ioapics.len(),
+            madt.overrides.len()
+        );
+    }
+
+    *ACPI.lock() = Some(Acpi { tables, madt, fadt });
+    true
+}
+
+pub fn madt() -> Option<Madt> {
+    ACPI.lock().as_ref()?.madt.clone()
+}
+
+pub fn fadt() -> Option<Fadt> {
+    ACPI.lock().as_ref()?.fadt
+}
+
+// The first table with `signature`, the DSDT can be found here too
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt> {
+    ACPI.lock()
+        .as_ref()?
+        .tables
+        .iter()
+        .find(|sdt| sdt.signature() == signature)
+        .copied()
+}
+
+// Physical address of the root table and the size of its entries, the XSDT
+// is used when there is one
+fn find_rsdp() -> Option<(usize, usize)> {
+    let ebda = unsafe { ptr::read(phys_to_virt(EBDA_SEGMENT_PTR) as *const u16) } as usize * 16;
+    let mut areas = Vec::new();
+    if ebda != 0 {
+        areas.push((ebda, ebda + EBDA_SEARCH_SIZE));
+    }
+    areas.push((BIOS_AREA_START, BIOS_AREA_END));
+
+    for (start, end) in areas {
+        for paddr in (start..end).step_by(16) {
+            let rsdp =
+                unsafe { slice::from_raw_parts(phys_to_virt(paddr) as *const u8, RSDP_V2_SIZE) };
+            if &rsdp[0..8] != RSDP_SIGNATURE || !checksum_ok(&rsdp[..RSDP_V1_SIZE]) {
+                continue;
+            }
+            let revision = rsdp[15];
+            let xsdt = read_u64(rsdp, 24).unwrap() as usize;
+            if revision >= 2 && xsdt != 0 && checksum_ok(rsdp) {
+                return Some((xsdt, 8));
+            }
+            return Some((read_u32(rsdp, 16).unwrap() as usize, 4));
+        }
+    }
+    None
+}
+
+// Tables often sit in ACPI memory above the usable RAM, which the physical
+// memory map doesn't reach. Pages it already covers keep their flags.
+unsafe fn map_phys(paddr: usize, len: usize, flags: PageFlags, pml4: &mut PML4) -> &'static [u8] {
+    let start = paddr & !0xfff;
+    let end = (paddr + len).next_multiple_of(0x1000);
+    for page in (start..end).step_by(0x1000) {
+        let vaddr = phys_to_virt(page);
+        if pml4.translate(vaddr).is_none() {
+            pml4.map_frame_4k(page, vaddr, flags);
+        }
+    }
+    slice::from_raw_parts(phys_to_virt(paddr) as *const u8, len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> Option<u8> {
+    bytes.get(offset).copied()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
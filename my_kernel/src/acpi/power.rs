@@ -0,0 +1,214 @@
+use core::arch::asm;
+use core::ptr;
+
+use super::fadt::{AddressSpace, Fadt, GenericAddress};
+use super::{fadt, find_table};
+use crate::memory::phys_map::phys_to_virt;
+use crate::println;
+
+// PM1 control register
+const SCI_EN: u32 = 1 << 0;
+const SLP_TYP_SHIFT: u32 = 10;
+const SLP_TYP_MASK: u32 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u32 = 1 << 13;
+
+// AML opcodes needed to read the \_S5 package
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_PREFIX: u8 = b'\\';
+
+// 8042 keyboard controller
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+// how often to poll hardware before giving up on it
+const SPIN_LIMIT: usize = 0x10000;
+
+/// Powers the machine off through ACPI sleep state S5, halts if that fails
+pub fn shutdown() -> ! {
+    println!("Powering off");
+    if let (Some(fadt), Some((slp_typ_a, slp_typ_b))) = (fadt(), s5_sleep_types()) {
+        unsafe {
+            enable_acpi_mode(&fadt);
+            // SLP_TYPb only matters when the control block is split in two
+            if let Some(pm1b) = fadt.pm1b_control {
+                enter_sleep_state(pm1b, slp_typ_b);
+            }
+            if let Some(pm1a) = fadt.pm1a_control {
+                enter_sleep_state(pm1a, slp_typ_a);
+            }
+        }
+        // writing SLP_EN takes a moment to kick in
+        for _ in 0..SPIN_LIMIT {
+            core::hint::spin_loop();
+        }
+    }
+    println!("ACPI power off failed, halting");
+    halt()
+}
+
+/// Resets the machine through the ACPI reset register, then the keyboard
+/// controller, and a triple fault as a last resort
+pub fn reboot() -> ! {
+    println!("Rebooting");
+    unsafe {
+        if let Some(fadt) = fadt() {
+            if let Some(reset) = fadt.reset_register {
+                write_register(reset, fadt.reset_value as u32);
+            }
+        }
+
+        for _ in 0..SPIN_LIMIT {
+            if inb(KBC_STATUS) & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        outb(KBC_STATUS, KBC_PULSE_RESET);
+        for _ in 0..SPIN_LIMIT {
+            core::hint::spin_loop();
+        }
+
+        // with an empty IDT the breakpoint can't be delivered, neither can the
+        // double fault that follows, so the cpu resets
+        let idtr = [0u64; 2];
+        asm!("lidt [{}]", "int3", in(reg) &idtr, options(noreturn));
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt");
+        }
+    }
+}
+
+// SLP_TYPa and SLP_TYPb from the \_S5 package of the DSDT
+fn s5_sleep_types() -> Option<(u32, u32)> {
+    let aml = find_table(b"DSDT")?.data();
+    let name = (0..aml.len().saturating_sub(4)).find(|&i| {
+        &aml[i..i + 4] == b"_S5_"
+            && ((i >= 1 && aml[i - 1] == NAME_OP)
+                || (i >= 2 && aml[i - 1] == ROOT_PREFIX && aml[i - 2] == NAME_OP))
+    })?;
+
+    let mut offset = name + 4;
+    if *aml.get(offset)? != PACKAGE_OP {
+        return None;
+    }
+    offset += 1;
+    // PkgLength, the top two bits of its first byte count the bytes after it
+    offset += (*aml.get(offset)? >> 6) as usize + 1;
+    // NumElements
+    offset += 1;
+
+    let (slp_typ_a, len) = aml_integer(aml.get(offset..)?)?;
+    let (slp_typ_b, _) = aml_integer(aml.get(offset + len..)?)?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+// A small integer constant and how many bytes it takes
+fn aml_integer(aml: &[u8]) -> Option<(u32, usize)> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        BYTE_PREFIX => Some((*aml.get(1)? as u32, 2)),
+        WORD_PREFIX => Some((u16::from_le_bytes([*aml.get(1)?, *aml.get(2)?]) as u32, 3)),
+        _ => None,
+    }
+}
+
+// Firmware that starts out in legacy mode switches to ACPI mode through the
+// SMI command port. Sleep states can't be entered before that.
+unsafe fn enable_acpi_mode(fadt: &Fadt) {
+    let pm1a = match fadt.pm1a_control {
+        Some(pm1a) => pm1a,
+        None => return,
+    };
+    if read_register(pm1a) & SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    outb(fadt.smi_command as u16, fadt.acpi_enable);
+    for _ in 0..SPIN_LIMIT {
+        if read_register(pm1a) & SCI_EN != 0 {
+            return;
+        }
+    }
+    println!("ACPI mode didn't come on");
+}
+
+unsafe fn enter_sleep_state(pm1_control: GenericAddress, slp_typ: u32) {
+    let value = read_register(pm1_control) & !SLP_TYP_MASK;
+    write_register(
+        pm1_control,
+        value | ((slp_typ << SLP_TYP_SHIFT) & SLP_TYP_MASK) | SLP_EN,
+    );
+}
+
+// Memory mapped registers are mapped by `init_acpi`
+unsafe fn read_register(reg: GenericAddress) -> u32 {
+    let value = match (reg.space, reg.bit_width) {
+        (AddressSpace::Io, 8) => inb(reg.address as u16) as u32,
+        (AddressSpace::Io, 16) => inw(reg.address as u16) as u32,
+        (AddressSpace::Io, _) => inl(reg.address as u16),
+        (AddressSpace::Memory, 8) => ptr::read_volatile(register_ptr::<u8>(reg)) as u32,
+        (AddressSpace::Memory, 16) => ptr::read_volatile(register_ptr::<u16>(reg)) as u32,
+        (AddressSpace::Memory, _) => ptr::read_volatile(register_ptr::<u32>(reg)),
+        (AddressSpace::Other(_), _) => 0,
+    };
+    value >> reg.bit_offset
+}
+
+unsafe fn write_register(reg: GenericAddress, value: u32) {
+    let value = value << reg.bit_offset;
+    match (reg.space, reg.bit_width) {
+        (AddressSpace::Io, 8) => outb(reg.address as u16, value as u8),
+        (AddressSpace::Io, 16) => outw(reg.address as u16, value as u16),
+        (AddressSpace::Io, _) => outl(reg.address as u16, value),
+        (AddressSpace::Memory, 8) => ptr::write_volatile(register_ptr(reg), value as u8),
+        (AddressSpace::Memory, 16) => ptr::write_volatile(register_ptr(reg), value as u16),
+        (AddressSpace::Memory, _) => ptr::write_volatile(register_ptr(reg), value),
+        (AddressSpace::Other(space), _) => {
+            println!("ACPI: can't write to address space {}", space)
+        }
+    }
+}
+
+fn register_ptr<T>(reg: GenericAddress) -> *mut T {
+    phys_to_virt(reg.address as usize) as *mut T
+}
+
+unsafe fn outb(port: u16, val: u8) {
+    asm!("outb %al, %dx", in("al") val, in("dx") port, options(att_syntax));
+}
+
+unsafe fn outw(port: u16, val: u16) {
+    asm!("outw %ax, %dx", in("ax") val, in("dx") port, options(att_syntax));
+}
+
+unsafe fn outl(port: u16, val: u32) {
+    asm!("outl %eax, %dx", in("eax") val, in("dx") port, options(att_syntax));
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let ret: u8;
+    asm!("inb %dx, %al", out("al") ret, in("dx") port, options(att_syntax));
+    ret
+}
+
+unsafe fn inw(port: u16) -> u16 {
+    let ret: u16;
+    asm!("inw %dx, %ax", out("ax") ret, in("dx") port, options(att_syntax));
+    ret
+}
+
+unsafe fn inl(port: u16) -> u32 {
+    let ret: u32;
+    asm!("inl %dx, %eax", out("eax") ret, in("dx") port, options(att_syntax));
+    ret
+}
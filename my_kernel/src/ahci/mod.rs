@@ -1,13 +1,12 @@
 use core::mem;
+use core::ptr;
 
-use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use crate::memory::{
-    heap::{translate_ref_to_phys, translate_usize_to_phys, translate_usize_to_virt},
-    page_table::PhysPage4KiB,
-};
+use crate::memory::frame_allocator::FrameAllocator;
+use crate::memory::phys_map::{phys_to_virt, virt_to_phys};
+use crate::println;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -278,28 +277,50 @@ const ATA_CMD_READ_DMA: u8 = 0xC8;
 const ATA_CMD_READ_DMA_EX: u8 = 0x25;
 #[allow(dead_code)]
 const ATA_CMD_WRITE_DMA: u8 = 0xCA;
-#[allow(dead_code)]
 const ATA_CMD_WRITE_DMA_EX: u8 = 0x35;
 
 const HBA_PX_IS_TFES: u32 = 1 << 30; // TFES - Task File Error Status
 
 pub const SECTOR_SIZE: usize = 512;
 
+// 48 bit LBA
+const MAX_LBA: u64 = 1 << 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    NoFreeSlot,
+    PortHung,
+    TaskFileError,
+    // zero sectors, more than the count register holds, or past the end of the lba range
+    BadSectorRange,
+    // more PRDT entries needed than the command table has
+    TransferTooLarge,
+    // buffer length does not match the number of sectors
+    BadBufferSize,
+}
+
 impl HbaPort {
-    pub fn port_rebase(&mut self, heap_regions: &Vec<(&PhysPage4KiB, usize)>) -> Box<PortSetup> {
+    // The controller only gets the start of each structure, so they are put
+    // in physically contiguous frames rather than on the heap
+    pub fn port_rebase(&mut self, frame_alloc: &mut FrameAllocator) -> &'static mut PortSetup {
         self.stop_cmd(); // Stop command engine
 
-        let mut port_setup = Box::<PortSetup>::default();
-
-        let cmd_list = unsafe {
-            (translate_ref_to_phys(heap_regions, &port_setup.cmd_list)) as *const _ as usize
+        let pages = mem::size_of::<PortSetup>().div_ceil(0x1000);
+        let paddr = frame_alloc
+            .allocate_contiguous(pages, 1)
+            .expect("No contiguous region for the AHCI port");
+        // all zeroes is the default
+        let port_setup = unsafe {
+            let vaddr = phys_to_virt(paddr);
+            ptr::write_bytes(vaddr as *mut u8, 0, pages * 0x1000);
+            &mut *(vaddr as *mut PortSetup)
         };
+
+        let cmd_list = virt_to_phys(&port_setup.cmd_list as *const _ as usize);
         self.clb = (cmd_list & 0xffffffff) as u32;
         self.clbu = ((cmd_list >> 32) & 0xffffffff) as u32;
 
-        let fis_entry = unsafe {
-            (translate_ref_to_phys(heap_regions, &port_setup.fis_entry)) as *const _ as usize
-        };
+        let fis_entry = virt_to_phys(&port_setup.fis_entry as *const _ as usize);
         self.fb = (fis_entry & 0xffffffff) as u32;
         self.fbu = ((fis_entry >> 32) & 0xffffffff) as u32;
 
@@ -307,10 +328,7 @@ impl HbaPort {
             port_setup.cmd_list[i].prdtl = 1024; // 8 prdt entries per command table
                                                  // 256 bytes per command table, 64+16+48+16*8
                                                  // Command table offset: 40K + 8K*portno + cmdheader_index*256
-            let cmd_table = unsafe {
-                (translate_ref_to_phys(heap_regions, &(port_setup.cmd_table[i]))) as *const _
-                    as usize
-            };
+            let cmd_table = virt_to_phys(&port_setup.cmd_table[i] as *const _ as usize);
             port_setup.cmd_list[i].ctba = (cmd_table & 0xffffffff) as u32;
             port_setup.cmd_list[i].ctbau = ((cmd_table >> 32) & 0xffffffff) as u32;
         }
@@ -350,111 +368,150 @@ impl HbaPort {
         }
     }
 
-    fn cmd_header<'a>(
-        &'a self,
-        slot: usize,
-        heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    ) -> &'a mut HbaCmdHeader {
+    fn cmd_header<'a>(&'a self, slot: usize) -> &'a mut HbaCmdHeader {
         let ptr = self.clb as usize + ((self.clbu as usize) << 32);
         let ptr = ptr + slot * mem::size_of::<HbaCmdHeader>();
-        let ptr = unsafe { translate_usize_to_virt(heap_regions, ptr) };
+        let ptr = phys_to_virt(ptr);
         unsafe { &mut *(ptr as *mut HbaCmdHeader) as &'a mut HbaCmdHeader }
     }
 
-    pub fn read(
+    pub fn read(&mut self, startl: u32, starth: u32, count: usize) -> Option<Vec<u8>> {
+        let mut buf: Vec<u8> = vec![0xaa; count * SECTOR_SIZE];
+        let lba = startl as u64 | ((starth as u64) << 32);
+
+        match self.issue_dma(
+            ATA_CMD_READ_DMA_EX,
+            lba,
+            count,
+            buf.as_mut_ptr() as usize,
+            false,
+        ) {
+            Ok(()) => Some(buf),
+            Err(e) => {
+                println!("Read disk error: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // `data` must be exactly `sectors` sectors long
+    pub fn write(&mut self, lba: u64, sectors: usize, data: &[u8]) -> Result<(), AhciError> {
+        if data.len() != sectors * SECTOR_SIZE {
+            return Err(AhciError::BadBufferSize);
+        }
+        self.issue_dma(
+            ATA_CMD_WRITE_DMA_EX,
+            lba,
+            sectors,
+            data.as_ptr() as usize,
+            true,
+        )
+    }
+
+    // Runs a DMA command on `count` sectors starting at `lba` and waits for it.
+    // `buf` must be a heap address with room for `count` sectors.
+    fn issue_dma(
         &mut self,
-        startl: u32,
-        starth: u32,
+        command: u8,
+        lba: u64,
         count: usize,
-        heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    ) -> Option<Vec<u8>> {
-        let mut buf: Vec<u8> = vec![0xaa; count * SECTOR_SIZE];
+        buf: usize,
+        write: bool,
+    ) -> Result<(), AhciError> {
+        if count == 0 || count > 0xffff || lba + count as u64 > MAX_LBA {
+            return Err(AhciError::BadSectorRange);
+        }
 
-        let mut count = count;
         self.is = u32::MAX; // Clear pending interrupt bits
         let mut spin = 0; // Spin lock timeout counter
-        let slot = self.find_cmdslot()?;
+        let slot = self.find_cmdslot().ok_or(AhciError::NoFreeSlot)?;
 
-        let cmdheader = self.cmd_header(slot, heap_regions);
+        let cmdheader = self.cmd_header(slot);
         let size = (mem::size_of::<FisRegH2d>() / mem::size_of::<u32>()) as u8;
         cmdheader.cfl(size); // Command FIS size
-        cmdheader.w(0); // Read from device
-        cmdheader.prdtl = (((count - 1) >> 4) + 1) as u16; // PRDT entries count
+        cmdheader.w(write);
 
-        let cmdtbl = cmdheader.cmd_table(heap_regions);
+        let cmdtbl = cmdheader.cmd_table();
 
         cmdtbl.clear();
 
-        // 8K bytes (16 sectors) per PRDT
-        let mut buf_addr = buf.as_mut_ptr() as usize;
-        buf_addr = unsafe { translate_usize_to_phys(heap_regions, buf_addr) };
-        let mut ind = 0;
-        for i in 0..(cmdheader.prdtl - 1) as usize {
-            cmdtbl.prdt_entry[i].dba = (buf_addr & 0xffffffff) as u32;
-            cmdtbl.prdt_entry[i].dbau = ((buf_addr >> 32) & 0xffffffff) as u32;
-            cmdtbl.prdt_entry[i].dbc = 8 * 1024 - 1; // 8K bytes (this value should always be set to 1 less than the actual value)
-            cmdtbl.prdt_entry[i].interrupt(true);
-            buf_addr += 2 * 4 * 1024; // 4K words
-            count -= 16; // 16 sectors
-            ind = i;
+        // The heap is only physically contiguous within a page,
+        // so each page of the buffer gets its own PRDT entry
+        let len = count * SECTOR_SIZE;
+        let mut offset = 0;
+        let mut entries = 0;
+        while offset < len {
+            if entries == cmdtbl.prdt_entry.len() {
+                return Err(AhciError::TransferTooLarge);
+            }
+            let addr = buf + offset;
+            let chunk = (0x1000 - (addr & 0xfff)).min(len - offset);
+            let phys_addr = virt_to_phys(addr);
+
+            let entry = &mut cmdtbl.prdt_entry[entries];
+            entry.dba = (phys_addr & 0xffffffff) as u32;
+            entry.dbau = ((phys_addr >> 32) & 0xffffffff) as u32;
+            entry.dbc = (chunk - 1) as u32; // this value should always be set to 1 less than the actual value
+            entry.interrupt(true);
+
+            offset += chunk;
+            entries += 1;
         }
-        // Last entry
-        cmdtbl.prdt_entry[ind].dba = (buf_addr & 0xffffffff) as u32;
-        cmdtbl.prdt_entry[ind].dbau = ((buf_addr >> 32) & 0xffffffff) as u32;
-        cmdtbl.prdt_entry[ind].dbc = ((count as u32) << 9) - 1; // 512 bytes per sector
-        cmdtbl.prdt_entry[ind].interrupt(true);
 
         // Setup command
         // FIS_REG_H2D *cmdfis = &cmdtbl.cfis;
-        let test: usize = &cmdtbl.cfis as *const _ as usize;
-        let cmdfis = unsafe { &mut *(test as *mut FisRegH2d) };
+        // the FIS is all bytes, so it fits anywhere in `cfis`
+        let cmdfis = unsafe { &mut *(cmdtbl.cfis.as_mut_ptr() as *mut FisRegH2d) };
+        cmdheader.prdtl = entries as u16; // PRDT entries count
 
         cmdfis.fis_type = FisType::RegH2d as u8;
         cmdfis.pmult = 0b10000000; // Command
-        cmdfis.command = ATA_CMD_READ_DMA_EX;
+        cmdfis.command = command;
 
-        cmdfis.lba0 = startl as u8;
-        cmdfis.lba1 = (startl >> 8) as u8;
-        cmdfis.lba2 = (startl >> 16) as u8;
+        cmdfis.lba0 = lba as u8;
+        cmdfis.lba1 = (lba >> 8) as u8;
+        cmdfis.lba2 = (lba >> 16) as u8;
         cmdfis.device = 1 << 6; // LBA mode
 
-        cmdfis.lba3 = (startl >> 24) as u8;
-        cmdfis.lba4 = starth as u8;
-        cmdfis.lba5 = (starth >> 8) as u8;
+        cmdfis.lba3 = (lba >> 24) as u8;
+        cmdfis.lba4 = (lba >> 32) as u8;
+        cmdfis.lba5 = (lba >> 40) as u8;
 
         cmdfis.countl = (count & 0xFF) as u8;
         cmdfis.counth = ((count >> 8) & 0xFF) as u8;
 
         // The below loop waits until the port is no longer busy before issuing a new command
-        while (self.tfd & (ATA_DEV_BUSY | ATA_DEV_DRQ)) != 0 && spin < 1000000 {
+        while unsafe { ptr::read_volatile(&self.tfd) } & (ATA_DEV_BUSY | ATA_DEV_DRQ) != 0
+            && spin < 1000000
+        {
             spin += 1;
         }
         if spin == 1000000 {
-            panic!("Port is hung\n");
+            return Err(AhciError::PortHung);
         }
 
-        self.ci = 1 << slot; // Issue command
+        unsafe {
+            ptr::write_volatile(&mut self.ci, 1 << slot); // Issue command
+        }
 
         // Wait for completion
         loop {
             // In some longer duration reads, it may be helpful to spin on the DPS bit
             // in the PxIS port field as well (1 << 5)
-            if (self.ci & (1 << slot)) == 0 {
+            if unsafe { ptr::read_volatile(&self.ci) } & (1 << slot) == 0 {
                 break;
             }
-            if (self.is & HBA_PX_IS_TFES) != 0
-            // Task file error
-            {
-                panic!("Read disk error\n");
+            if unsafe { ptr::read_volatile(&self.is) } & HBA_PX_IS_TFES != 0 {
+                return Err(AhciError::TaskFileError);
             }
         }
 
         // Check again
-        if self.is & HBA_PX_IS_TFES != 0 {
-            panic!("Read disk error\n");
+        if unsafe { ptr::read_volatile(&self.is) } & HBA_PX_IS_TFES != 0 {
+            return Err(AhciError::TaskFileError);
         }
 
-        Some(buf)
+        Ok(())
     }
 
     // Find a free command list slot
@@ -519,18 +576,23 @@ struct HbaCmdHeader {
 }
 
 impl HbaCmdHeader {
-    fn cmd_table<'a>(&'a self, heap_regions: &Vec<(&PhysPage4KiB, usize)>) -> &'a mut HbaCmdTbl {
+    fn cmd_table<'a>(&'a self) -> &'a mut HbaCmdTbl {
         let ptr = self.ctba as usize + ((self.ctbau as usize) << 32);
-        let ptr = unsafe { translate_usize_to_virt(heap_regions, ptr) };
+        let ptr = phys_to_virt(ptr);
         unsafe { &mut *(ptr as *mut HbaCmdTbl) as &'a mut HbaCmdTbl }
     }
 
     fn cfl(&mut self, cfl: u8) {
-        self.config = cfl & 0b11111;
+        self.config = (self.config & !0b11111) | (cfl & 0b11111);
     }
 
-    fn w(&mut self, cfl: u8) {
-        self.config = cfl & 0b1000000;
+    // set for host to device transfers
+    fn w(&mut self, write: bool) {
+        if write {
+            self.config |= 0b1000000;
+        } else {
+            self.config &= !0b1000000;
+        }
     }
 }
 
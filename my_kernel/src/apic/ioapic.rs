@@ -0,0 +1,278 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+use crate::interrupts::irq::FIRST_IRQ_VECTOR;
+use crate::interrupts::without_interrupts;
+use crate::memory::page_table::{PageFlags, PML4};
+
+// Where the I/O APIC usually sits, the MADT has the real address
+pub const IOAPIC_DEFAULT_BASE: usize = 0xFEC0_0000;
+
+// the registers are reached through a select and a data window
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const REGISTER_WINDOW: usize = 0x20;
+
+const IOAPICVER: u32 = 0x01;
+// two registers per entry, low half first
+const IOREDTBL: u32 = 0x10;
+
+const ENTRY_MASKED: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+// An ISA IRQ that is not wired to the GSI of the same number, or not signalled
+// the ISA way (active high, edge triggered). These come from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+// Where and how one interrupt input is delivered, fixed delivery to a single
+// local APIC by its id
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub destination: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    fn bits(&self) -> u64 {
+        let mut data = self.vector as u64;
+        if self.polarity == Polarity::ActiveLow {
+            data |= 1 << 13;
+        }
+        if self.trigger == TriggerMode::Level {
+            data |= 1 << 15;
+        }
+        if self.masked {
+            data |= ENTRY_MASKED;
+        }
+        data | ((self.destination as u64) << 56)
+    }
+}
+
+pub struct IoApic {
+    base: usize,
+    // first GSI this I/O APIC handles
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    /// Identity maps the I/O APIC at `base` and masks all of its inputs. The
+    /// page may already be mapped, the MADT can put several I/O APICs on one.
+    /// # Safety
+    /// `base` must be the address of an I/O APIC, and its register pages must be
+    /// unmapped or identity mapped uncached in `pml4`
+    pub unsafe fn new(base: usize, gsi_base: u32, pml4: &mut PML4) -> Self {
+        // the register window is small but need not sit inside one page
+        let first_page = base & 0xffff_ffff_ffff_f000;
+        let last_page = (base + REGISTER_WINDOW - 1) & 0xffff_ffff_ffff_f000;
+        for page in (first_page..=last_page).step_by(0x1000) {
+            match pml4.translate(page) {
+                None => pml4.map_frame_4k(
+                    page,
+                    page,
+                    PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::UNCACHED,
+                ),
+                Some(paddr) if paddr == page => {}
+                Some(paddr) => panic!("I/O APIC page {:#x} is mapped to {:#x}", page, paddr),
+            }
+        }
+
+        let mut ioapic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        ioapic.entries = ((ioapic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        // nothing gets through until someone routes it
+        for index in 0..ioapic.entries {
+            ioapic.write_entry(index, ENTRY_MASKED);
+        }
+        ioapic
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    fn read_entry(&self, index: u32) -> u64 {
+        unsafe {
+            let low = self.read(IOREDTBL + index * 2);
+            let high = self.read(IOREDTBL + index * 2 + 1);
+            ((high as u64) << 32) | low as u64
+        }
+    }
+
+    fn write_entry(&mut self, index: u32, data: u64) {
+        unsafe {
+            // masked while the halves disagree
+            self.write(IOREDTBL + index * 2, (data as u32) | ENTRY_MASKED as u32);
+            self.write(IOREDTBL + index * 2 + 1, (data >> 32) as u32);
+            self.write(IOREDTBL + index * 2, data as u32);
+        }
+    }
+
+    pub fn set_entry(&mut self, gsi: u32, entry: RedirectionEntry) {
+        assert!(self.handles(gsi), "GSI {} is not on this I/O APIC", gsi);
+        self.write_entry(gsi - self.gsi_base, entry.bits());
+    }
+
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) {
+        assert!(self.handles(gsi), "GSI {} is not on this I/O APIC", gsi);
+        let index = gsi - self.gsi_base;
+        let data = self.read_entry(index);
+        if masked {
+            self.write_entry(index, data | ENTRY_MASKED);
+        } else {
+            self.write_entry(index, data & !ENTRY_MASKED);
+        }
+    }
+}
+
+// Every I/O APIC in the system and how the ISA IRQs are wired to them
+struct IrqRouting {
+    ioapics: Vec<IoApic>,
+    overrides: Vec<InterruptSourceOverride>,
+    // local APIC all interrupts are sent to
+    destination: u8,
+}
+
+// Handlers may mask their GSI, so it is only locked with interrupts disabled
+static IRQ_ROUTING: spin::Mutex<Option<IrqRouting>> = spin::Mutex::new(None);
+
+/// Sets up the I/O APICs at `ioapics`, given as (address, first GSI), with
+/// all their inputs masked. Routed interrupts go to the local APIC `destination`.
+/// # Safety
+/// Same as `IoApic::new` for each of them
+pub unsafe fn init_ioapics(
+    ioapics: &[(usize, u32)],
+    overrides: Vec<InterruptSourceOverride>,
+    destination: u8,
+    pml4: &mut PML4,
+) {
+    let ioapics = ioapics
+        .iter()
+        .map(|&(base, gsi_base)| IoApic::new(base, gsi_base, pml4))
+        .collect();
+    without_interrupts(|| {
+        *IRQ_ROUTING.lock() = Some(IrqRouting {
+            ioapics,
+            overrides,
+            destination,
+        });
+    });
+}
+
+/// The GSI an ISA IRQ arrives on and how it is signalled there
+pub fn isa_irq_source(irq: u8) -> (u32, Polarity, TriggerMode) {
+    let source = without_interrupts(|| {
+        let routing = IRQ_ROUTING.lock();
+        routing
+            .as_ref()
+            .and_then(|routing| routing.overrides.iter().find(|source| source.irq == irq))
+            .copied()
+    });
+    match source {
+        Some(source) => (source.gsi, source.polarity, source.trigger),
+        None => (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge),
+    }
+}
+
+/// Delivers `gsi` as `vector`, it stays masked until `unmask_gsi`
+/// Returns false if no I/O APIC handles `gsi`.
+pub fn route_gsi(gsi: u32, vector: usize, polarity: Polarity, trigger: TriggerMode) -> bool {
+    assert!(
+        (FIRST_IRQ_VECTOR..256).contains(&vector),
+        "Invalid vector {}",
+        vector
+    );
+    without_interrupts(|| {
+        let mut routing = IRQ_ROUTING.lock();
+        let routing = match routing.as_mut() {
+            Some(routing) => routing,
+            None => return false,
+        };
+        let entry = RedirectionEntry {
+            vector: vector as u8,
+            destination: routing.destination,
+            polarity,
+            trigger,
+            masked: true,
+        };
+        match routing
+            .ioapics
+            .iter_mut()
+            .find(|ioapic| ioapic.handles(gsi))
+        {
+            Some(ioapic) => {
+                ioapic.set_entry(gsi, entry);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Delivers ISA IRQ `irq` as `vector`, honoring the source overrides
+/// Returns the GSI it arrives on, which stays masked until `unmask_gsi`.
+pub fn route_isa_irq(irq: u8, vector: usize) -> Option<u32> {
+    let (gsi, polarity, trigger) = isa_irq_source(irq);
+    if route_gsi(gsi, vector, polarity, trigger) {
+        Some(gsi)
+    } else {
+        None
+    }
+}
+
+pub fn mask_gsi(gsi: u32) {
+    set_gsi_masked(gsi, true);
+}
+
+pub fn unmask_gsi(gsi: u32) {
+    set_gsi_masked(gsi, false);
+}
+
+fn set_gsi_masked(gsi: u32, masked: bool) {
+    without_interrupts(|| {
+        let mut routing = IRQ_ROUTING.lock();
+        let ioapic = routing
+            .as_mut()
+            .and_then(|routing| {
+                routing
+                    .ioapics
+                    .iter_mut()
+                    .find(|ioapic| ioapic.handles(gsi))
+            })
+            .unwrap_or_else(|| panic!("No I/O APIC handles GSI {}", gsi));
+        ioapic.set_masked(gsi, masked);
+    });
+}
@@ -1,13 +1,16 @@
+pub mod ioapic;
+
 use core::arch::asm;
 
 use crate::cpu::{get_cpuid_feature_rdx, read_msr, write_msr};
-use crate::interrupts::ExtraInterrupts;
-use crate::memory::page_table::PML4;
+use crate::memory::page_table::{PageFlags, PML4};
 
 const APIC_FEATURE_BIT: u16 = 9;
 
 const IA32_APIC_BASE_MSR: u32 = 0x1B;
 
+pub const APIC_TIMER_VECTOR: usize = 32;
+
 pub fn check_apic() -> bool {
     get_cpuid_feature_rdx(APIC_FEATURE_BIT)
 }
@@ -28,6 +31,12 @@ pub unsafe fn enable_apic(base: u64) {
     *((base + 0xf0) as *mut u32) |= 0x100;
 }
 
+/// # Safety
+/// Assumes `base` is a valid address to an apic
+pub unsafe fn get_apic_id(base: u64) -> u8 {
+    (*((base + 0x20) as *const u32) >> 24) as u8
+}
+
 /// # Safety
 /// Assumes `base` is a valid address to an apic
 pub unsafe fn set_apic_tpr(base: u64, val: u32) {
@@ -36,8 +45,8 @@ pub unsafe fn set_apic_tpr(base: u64, val: u32) {
 
 /// # Safety
 /// Assumes `base` is a valid address to an apic
-pub unsafe fn apic_timer_int_index(base: u64, index: ExtraInterrupts) {
-    *((base + 0x320) as *mut u32) = 0x20000 | index as u32;
+pub unsafe fn apic_timer_int_index(base: u64, vector: usize) {
+    *((base + 0x320) as *mut u32) = 0x20000 | vector as u32;
 }
 
 /// # Safety
@@ -63,7 +72,11 @@ pub unsafe fn apic_end_of_interrupt(base: u64) {
 pub fn ident_map_apic_page(base: u64, pml4: &mut PML4) {
     assert_eq!(base & 0xffff_ffff_ffff_f000, base);
     unsafe {
-        pml4.map_frame_4k(base as usize, base as usize, true, true, None);
+        pml4.map_frame_4k(
+            base as usize,
+            base as usize,
+            PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::UNCACHED,
+        );
     }
 }
 
@@ -81,7 +94,7 @@ pub fn disable_pic() {
 /// Assumes `base` is a valid address to an apic
 pub unsafe fn start_apic_timer(base: u64) {
     apic_timer_set_divide(base, 0b1011);
-    apic_timer_int_index(base, ExtraInterrupts::ApicTimer);
+    apic_timer_int_index(base, APIC_TIMER_VECTOR);
     apic_timer_set_count(base, 10000000);
 
     apic_end_of_interrupt(base);
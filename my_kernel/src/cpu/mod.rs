@@ -8,12 +8,58 @@ pub fn get_cpuid_feature_rdx(bit: u16) -> bool {
     features & (1_u64 << bit) != 0
 }
 
+// Extended features from CPUID leaf 0x80000001
+pub fn get_cpuid_ext_feature_rdx(bit: u16) -> bool {
+    let features: u64;
+    unsafe {
+        asm!(
+            "push rbx; cpuid; pop rbx",
+            inout("rax") 0x8000_0001_u64 => _,
+            out("rcx") _,
+            out("rdx") features,
+        );
+    }
+    features & (1_u64 << bit) != 0
+}
+
 const MSR_FEATURE_BIT: u16 = 5;
+const NX_FEATURE_BIT: u16 = 20;
+const PAGE_1GB_FEATURE_BIT: u16 = 26;
 
 pub fn check_msr() -> bool {
     get_cpuid_feature_rdx(MSR_FEATURE_BIT)
 }
 
+pub fn check_nx() -> bool {
+    get_cpuid_ext_feature_rdx(NX_FEATURE_BIT)
+}
+
+pub fn check_1gib_pages() -> bool {
+    get_cpuid_ext_feature_rdx(PAGE_1GB_FEATURE_BIT)
+}
+
+const IA32_PAT: u32 = 0x277;
+const IA32_EFER: u32 = 0xC000_0080;
+const EFER_NXE: u64 = 1 << 11;
+
+// The power on PAT with entry 1 changed from write through to write combining
+const PAT_VALUE: u64 = 0x0007_0406_0007_0106;
+
+/// Lets page table entries set the no execute bit, before that the bit is
+/// reserved and faults
+/// # Safety
+/// The cpu must support NX, see `check_nx`
+pub unsafe fn enable_nx() {
+    write_msr(IA32_EFER, read_msr(IA32_EFER) | EFER_NXE);
+}
+
+/// Sets up the memory types `PageFlags` selects through the PAT
+/// # Safety
+/// Nothing may be mapped write through, it becomes write combining
+pub unsafe fn init_pat() {
+    write_msr(IA32_PAT, PAT_VALUE);
+}
+
 /// # Safety
 /// `msr` must be a valid msr
 pub unsafe fn write_msr(msr: u32, value: u64) {
@@ -31,3 +77,21 @@ pub unsafe fn read_msr(msr: u32) -> u64 {
 
     ((high as u64) << 32) | (low as u64)
 }
+
+// Address that caused the last page fault
+pub fn read_cr2() -> usize {
+    let cr2: usize;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2);
+    }
+    cr2
+}
+
+// Physical address of the active PML4
+pub fn read_cr3() -> usize {
+    let cr3: usize;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3);
+    }
+    cr3 & 0x000f_ffff_ffff_f000
+}
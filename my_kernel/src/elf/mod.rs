@@ -1,4 +1,5 @@
 use crate::bootloader_structs::BootInfo;
+use crate::memory::page_table::PageFlags;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 use core::mem;
@@ -17,6 +18,24 @@ pub struct ProgHeaderEntry {
     pub align: u64,
 }
 
+// bits of `ProgHeaderEntry::flags`
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+
+impl ProgHeaderEntry {
+    // Flags to map this segment with, it is always readable
+    pub fn page_flags(&self) -> PageFlags {
+        let mut flags = PageFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct SecHeaderEntry {
@@ -1,180 +1,210 @@
 use alloc::vec::Vec;
 
 use core::mem;
-use core::slice;
+use core::ptr;
 
 use crate::elf::ProgHeaderEntry;
-use crate::memory::frame_allocator::LinkedListFrameAllocator;
-use crate::memory::heap::HEAP_SIZE;
+use crate::memory::frame_allocator::{FrameAllocator, FrameOwner};
 use crate::memory::heap::HEAP_START;
-use crate::memory::mappings::ident_map_vga_buf;
+use crate::memory::mappings::{ident_map_vga_buf, map_frame_table};
+use crate::memory::page_table::PageFlags;
 use crate::memory::page_table::PhysPage4KiB;
+use crate::memory::page_table::VirtPage4KiB;
 use crate::memory::page_table::PML4;
+use crate::memory::phys_map::PHYS_MAP_START;
 use crate::memory::stack::KERN_STACK_TOP;
 use crate::memory::stack::STACK_SIZE;
 
 use crate::println;
+use crate::process::USER_HEAP_START;
 
-const ELF_STAGING_AREA: usize = 0x0000_4000_0000_0000;
+pub const ELF_STAGING_AREA: usize = 0x0000_4000_0000_0000;
 
-const USER_PROG_AREA: usize = 0x0000_2000_0000_0000;
+pub const USER_PROG_AREA: usize = 0x0000_2000_0000_0000;
+// segments have to end below the user heap
+const USER_PROG_MAX_SIZE: usize = USER_HEAP_START - USER_PROG_AREA;
 
 pub struct ElfLoader {}
 
 impl ElfLoader {
     pub fn load(
         file_data: Vec<u8>,
-        frame_alloc: &mut LinkedListFrameAllocator,
+        frame_alloc: &mut FrameAllocator,
         kernel_pml4: &mut PML4,
-        heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
         stack_phys: *const PhysPage4KiB,
-    ) -> (&'static mut PML4, u64) {
-        let magic: u32 =
-            u32::from_le_bytes(file_data[0..4].try_into().expect("Couldn't get offset [1]"));
-        assert_eq!(magic, 0x464C457F, "magic was {:#x}", magic);
+    ) -> Option<(&'static mut PML4, u64)> {
+        let magic: u32 = u32::from_le_bytes(file_data.get(0..4)?.try_into().ok()?);
+        if magic != 0x464C457F {
+            println!("Not an ELF, magic was {:#x}", magic);
+            return None;
+        }
 
-        let prog_headers = ElfLoader::get_prog_header_entries(&file_data);
+        let prog_headers = match ElfLoader::get_prog_header_entries(&file_data) {
+            Some(prog_headers) => prog_headers,
+            None => {
+                println!("Invalid ELF program headers");
+                return None;
+            }
+        };
+        let entry = u64::from_le_bytes(file_data.get(0x18..0x20)?.try_into().ok()?);
+        if entry as usize >= USER_PROG_MAX_SIZE {
+            println!("ELF entry point {:#x} is outside the program area", entry);
+            return None;
+        }
 
-        let user_pml4 = PML4::new(Some(heap_regions));
+        let user_pml4 = ElfLoader::new_user_pml4(frame_alloc, kernel_pml4, stack_phys);
 
         ElfLoader::map_elf_and_copy(
             &prog_headers,
             frame_alloc,
             kernel_pml4,
             user_pml4,
-            heap_regions,
             &file_data,
         );
 
         // TODO: enable once they have reloc
         // ElfLoader::fix_relocatable_addrs(&prog_headers);
 
-        ElfLoader::map_kernel_stack(user_pml4, stack_phys, heap_regions);
-        println!("about to map kern heap");
-        ElfLoader::map_heap(heap_regions, user_pml4);
-        println!("done map kern heap");
-        ident_map_vga_buf(user_pml4, Some(heap_regions));
-
-        let entry: u64 = u64::from_le_bytes(
-            file_data[0x18..0x20]
-                .try_into()
-                .expect("Couldn't get offset"),
-        );
         let entry = entry + USER_PROG_AREA as u64;
 
-        (user_pml4, entry)
+        Some((user_pml4, entry))
     }
 
-    fn get_prog_header_entries(data: &[u8]) -> Vec<ProgHeaderEntry> {
-        let ph_off = usize::from_le_bytes(
-            data[0x20..0x28]
-                .try_into()
-                .expect("Couldn't get offset [0]"),
-        );
-
-        let ph_ent_size = u16::from_le_bytes(
-            data[0x36..0x38]
-                .try_into()
-                .expect("Couldn't get offset [0]"),
-        );
-
-        assert_eq!(ph_ent_size as usize, mem::size_of::<ProgHeaderEntry>());
+    // Page table for a new process with the kernel stack, heap, frame table and
+    // vga buffer already mapped. The kernel ELF is mapped separately.
+    pub fn new_user_pml4(
+        frame_alloc: &FrameAllocator,
+        kernel_pml4: &PML4,
+        stack_phys: *const PhysPage4KiB,
+    ) -> &'static mut PML4 {
+        let user_pml4 = PML4::new();
+        ElfLoader::map_kernel_stack(user_pml4, stack_phys);
+        // shared so the process sees the heap grow
+        user_pml4.share_entry(kernel_pml4, HEAP_START);
+        user_pml4.share_entry(kernel_pml4, PHYS_MAP_START);
+        // page faults are handled on the page table of the process
+        map_frame_table(frame_alloc, user_pml4);
+        ident_map_vga_buf(user_pml4);
+        user_pml4
+    }
 
-        let ph_ent_num = u16::from_le_bytes(
-            data[0x38..0x3a]
-                .try_into()
-                .expect("Couldn't get offset [0]"),
-        );
+    // The program headers of `data`, None unless every loadable segment is
+    // backed by the file, fits in the program area and has pages of its own
+    fn get_prog_header_entries(data: &[u8]) -> Option<Vec<ProgHeaderEntry>> {
+        let ph_off = u64::from_le_bytes(data.get(0x20..0x28)?.try_into().ok()?) as usize;
+        let ph_ent_size = u16::from_le_bytes(data.get(0x36..0x38)?.try_into().ok()?) as usize;
+        let ph_ent_num = u16::from_le_bytes(data.get(0x38..0x3a)?.try_into().ok()?) as usize;
 
-        let prog_headers = {
-            let ptr = (&data[0] as *const _ as usize + ph_off) as *const ProgHeaderEntry;
-            unsafe { slice::from_raw_parts(ptr, ph_ent_num as usize) }
-        };
-
-        let mut vec = Vec::new();
-        for entry in prog_headers {
-            vec.push(entry.clone());
+        if ph_ent_size != mem::size_of::<ProgHeaderEntry>() {
+            return None;
         }
-        vec
+        let table = data.get(ph_off..ph_off.checked_add(ph_ent_size * ph_ent_num)?)?;
+        let prog_headers: Vec<ProgHeaderEntry> = table
+            .chunks_exact(ph_ent_size)
+            .map(|entry| unsafe { ptr::read_unaligned(entry.as_ptr() as *const ProgHeaderEntry) })
+            .collect();
+
+        let mut loaded: Vec<(usize, usize)> = Vec::new();
+        for entry in prog_headers.iter().filter(|entry| entry.seg_type == 0x1) {
+            let file_end = entry.offset.checked_add(entry.file_size as usize)?;
+            if file_end > data.len() || entry.file_size > entry.mem_size {
+                return None;
+            }
+            entry.v_addr.checked_add(entry.mem_size as usize)?;
+            let (start_page, pages) = segment_pages(entry);
+            let end = start_page + pages * 0x1000;
+            if end > USER_PROG_MAX_SIZE {
+                return None;
+            }
+            // each page is allocated for a single segment
+            if loaded
+                .iter()
+                .any(|&(start, stop)| start_page < stop && start < end)
+            {
+                return None;
+            }
+            loaded.push((start_page, end));
+        }
+        Some(prog_headers)
     }
 
     fn map_elf_and_copy(
         prog_headers: &Vec<ProgHeaderEntry>,
-        frame_alloc: &mut LinkedListFrameAllocator,
+        frame_alloc: &mut FrameAllocator,
         kernel_pml4: &mut PML4,
         user_pml4: &mut PML4,
-        heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
         data: &[u8],
     ) {
         for entry in prog_headers {
             if entry.seg_type == 0x1 {
-                let start_page = entry.v_addr & 0xfffffffffffff000; // align to 0x1000
-                let end_page = (entry.v_addr + entry.mem_size as usize) & 0xfffffffffffff000; // align to 0x1000
-                let pages = ((end_page - start_page) / 0x1000) + 1;
+                let (start_page, pages) = segment_pages(entry);
 
                 for page in 0..pages {
                     let seg_offset = start_page;
                     let staging_virt_page = ELF_STAGING_AREA + seg_offset + page * 0x1000;
                     let user_virt_page = USER_PROG_AREA + seg_offset + page * 0x1000;
                     let (_kern_virt_page, phys_page) = frame_alloc
-                        .allocate_and_map(kernel_pml4, staging_virt_page, heap_regions)
+                        .allocate_and_map(
+                            kernel_pml4,
+                            staging_virt_page,
+                            PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+                        )
                         .unwrap();
+                    frame_alloc.set_owner(phys_page, FrameOwner::User);
                     unsafe {
+                        // frames are not zeroed and anything not copied below is bss
+                        core::ptr::write_bytes(staging_virt_page as *mut u8, 0, 0x1000);
                         user_pml4.map_frame_4k(
                             phys_page,
                             user_virt_page,
-                            true,
-                            true,
-                            Some(heap_regions),
+                            entry.page_flags() | PageFlags::USER,
                         );
                     }
                 }
 
-                let start_addr = entry.v_addr + ELF_STAGING_AREA;
-                let start_index = entry.offset;
-                // TODO: optimize to be not byte by byte
-                for i in 0..entry.file_size as usize {
-                    let addr = (i + start_addr) as *mut u8;
+                // checked against the file length by `get_prog_header_entries`
+                let contents = &data[entry.offset..entry.offset + entry.file_size as usize];
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        contents.as_ptr(),
+                        (entry.v_addr + ELF_STAGING_AREA) as *mut u8,
+                        contents.len(),
+                    );
+                }
+
+                // the frames stay mapped in the user pml4, drop the staging
+                // mapping so the next program can be loaded
+                for page in 0..pages {
+                    let staging_virt_page = ELF_STAGING_AREA + start_page + page * 0x1000;
                     unsafe {
-                        *addr = data[i + start_index];
+                        kernel_pml4.unmap_frame_4k(&*(staging_virt_page as *const VirtPage4KiB));
                     }
                 }
             }
         }
     }
 
-    pub fn map_kernel_stack(
-        pml4: &mut PML4,
-        stack_phys: *const PhysPage4KiB,
-        heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-    ) {
+    pub fn map_kernel_stack(pml4: &mut PML4, stack_phys: *const PhysPage4KiB) {
         let kern_top_page = KERN_STACK_TOP & 0xfffffffffffff000;
         let kern_bot_page = (KERN_STACK_TOP - STACK_SIZE) & 0xfffffffffffff000;
-        let stack_top = stack_phys as usize;
-
-        for offset in (0..kern_top_page - kern_bot_page).step_by(0x1000) {
-            let paddr = stack_top - offset;
-            let vaddr = kern_top_page - offset - 0x1000;
-            unsafe {
-                pml4.map_frame_4k(paddr, vaddr, true, true, Some(heap_regions));
-            }
+        let len = kern_top_page - kern_bot_page;
+        // `stack_phys` is the last page of the stack
+        let stack_bottom = stack_phys as usize + 0x1000 - len;
+
+        unsafe {
+            pml4.map_range(
+                stack_bottom,
+                kern_bot_page,
+                len,
+                PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+            );
         }
     }
+}
 
-    pub fn map_heap(heap_regions: &Vec<(&'static PhysPage4KiB, usize)>, pml4: &mut PML4) {
-        let mut vpage = HEAP_START;
-        for (start_page, num_pages) in heap_regions {
-            let start_page = *start_page as *const PhysPage4KiB as usize;
-            for page in 0..*num_pages {
-                let phys_page = start_page + (page * 0x1000);
-                unsafe {
-                    pml4.map_frame_4k(phys_page, vpage, true, true, Some(heap_regions));
-                }
-                vpage += 0x1000;
-            }
-        }
-        if vpage != HEAP_START + HEAP_SIZE {
-            panic!("Regions did not cover entire heap");
-        }
-    }
+// First page of `entry` in the program area and how many pages it takes
+fn segment_pages(entry: &ProgHeaderEntry) -> (usize, usize) {
+    let start_page = entry.v_addr & 0xfffffffffffff000; // align to 0x1000
+    let end_page = (entry.v_addr + entry.mem_size as usize) & 0xfffffffffffff000; // align to 0x1000
+    (start_page, ((end_page - start_page) / 0x1000) + 1)
 }
@@ -0,0 +1,35 @@
+use alloc::vec::Vec;
+
+use crate::ahci::{HbaPort, SECTOR_SIZE};
+use crate::fs::{FsError, BLOCK_SIZE};
+use crate::println;
+
+const SECTORS_PER_BLOCK: usize = BLOCK_SIZE / SECTOR_SIZE;
+
+// Block level access to an AHCI port for the filesystem
+pub struct Disk<'a> {
+    port: &'a mut HbaPort,
+}
+
+impl<'a> Disk<'a> {
+    pub fn new(port: &'a mut HbaPort) -> Self {
+        Disk { port }
+    }
+
+    pub fn read_block(&mut self, block: u32) -> Result<Vec<u8>, FsError> {
+        let lba = block as usize * SECTORS_PER_BLOCK;
+        self.port
+            .read(lba as u32, (lba >> 32) as u32, SECTORS_PER_BLOCK)
+            .filter(|data| data.len() == BLOCK_SIZE)
+            .ok_or(FsError::Io)
+    }
+
+    // `data` must be exactly one block
+    pub fn write_block(&mut self, block: u32, data: &[u8]) -> Result<(), FsError> {
+        let lba = block as u64 * SECTORS_PER_BLOCK as u64;
+        self.port.write(lba, SECTORS_PER_BLOCK, data).map_err(|e| {
+            println!("Write disk error: {:?}", e);
+            FsError::Io
+        })
+    }
+}
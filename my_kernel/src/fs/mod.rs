@@ -1,107 +1,946 @@
+pub mod disk;
+pub mod vfs;
+
 use core::convert::TryInto;
+use core::fmt;
 use core::str;
 
 use alloc::borrow::ToOwned;
 use alloc::vec;
 use alloc::{string::String, vec::Vec};
 
-use crate::ahci::{HbaPort, SECTOR_SIZE};
-use crate::memory::page_table::PhysPage4KiB;
+use crate::ahci::HbaPort;
+use crate::kernel_data::KERNEL_DATA;
+use disk::Disk;
+use vfs::{FileSystem, NodeId, OpenFlags, Stat};
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const MAX_NAME_LEN: usize = 56;
+
+const FS_MAGIC: u32 = 0x34127788;
+const FS_VERSION: u32 = 1;
+
+const INODE_SIZE: usize = 64;
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+const DIRECT_BLOCKS: usize = 10;
+const PTRS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+const MAX_FILE_BLOCKS: usize = DIRECT_BLOCKS + PTRS_PER_BLOCK;
+pub const MAX_FILE_SIZE: u64 = (MAX_FILE_BLOCKS * BLOCK_SIZE) as u64;
+
+const DIR_ENTRY_SIZE: usize = 64;
+const BITS_PER_BLOCK: u32 = (BLOCK_SIZE * 8) as u32;
+
+// inode 0 marks an empty directory slot so it is never used
+const ROOT_INODE: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    Io,
+    Corrupt,
+    NotFound,
+    AlreadyExists,
+    InvalidName,
+    NoSpace,
+    NoInodes,
+    FileTooLarge,
+    NotAFile,
+    NotADirectory,
+    DirectoryNotEmpty,
+    InvalidPath,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    Free = 0,
+    File = 1,
+    Dir = 2,
+}
+
+impl InodeKind {
+    fn from_u16(kind: u16) -> Result<Self, FsError> {
+        match kind {
+            0 => Ok(InodeKind::Free),
+            1 => Ok(InodeKind::File),
+            2 => Ok(InodeKind::Dir),
+            _ => Err(FsError::Corrupt),
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/*
+Block 0 of the disk, everything else is located through it:
+
+    | superblock | free bitmap | inode table | data blocks ... |
+*/
+#[derive(Debug, Clone)]
+struct SuperBlock {
+    total_blocks: u32,
+    inode_count: u32,
+    bitmap_start: u32,
+    bitmap_blocks: u32,
+    inode_table_start: u32,
+    inode_table_blocks: u32,
+    data_start: u32,
+}
+
+impl SuperBlock {
+    fn parse(data: &[u8]) -> Result<Self, FsError> {
+        if read_u32(data, 0x0) != FS_MAGIC
+            || read_u32(data, 0x4) != FS_VERSION
+            || read_u32(data, 0x8) as usize != BLOCK_SIZE
+        {
+            return Err(FsError::Corrupt);
+        }
+        let sb = SuperBlock {
+            total_blocks: read_u32(data, 0xc),
+            inode_count: read_u32(data, 0x10),
+            bitmap_start: read_u32(data, 0x14),
+            bitmap_blocks: read_u32(data, 0x18),
+            inode_table_start: read_u32(data, 0x1c),
+            inode_table_blocks: read_u32(data, 0x20),
+            data_start: read_u32(data, 0x24),
+        };
+
+        // the regions have to follow each other and fit on the disk
+        let bitmap_end = sb.bitmap_start as u64 + sb.bitmap_blocks as u64;
+        let inodes_end = sb.inode_table_start as u64 + sb.inode_table_blocks as u64;
+        if sb.bitmap_start == 0
+            || (sb.bitmap_blocks as u64) * (BITS_PER_BLOCK as u64) < sb.total_blocks as u64
+            || sb.inode_table_start as u64 != bitmap_end
+            || (sb.inode_table_blocks as u64) * (INODES_PER_BLOCK as u64) < sb.inode_count as u64
+            || sb.data_start as u64 != inodes_end
+            || sb.data_start >= sb.total_blocks
+            || sb.inode_count <= ROOT_INODE
+        {
+            return Err(FsError::Corrupt);
+        }
+        Ok(sb)
+    }
+}
+
+/*
+On disk inode, 64 bytes:
+
+    0x00 kind u16, 0x02 links u16, 0x08 size u64,
+    0x10 direct block pointers [u32; 10], 0x38 indirect block pointer u32
+
+A block pointer of 0 is a hole and reads back as zeroes.
+*/
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub kind: InodeKind,
+    pub links: u16,
+    pub size: u64,
+    direct: [u32; DIRECT_BLOCKS],
+    indirect: u32,
+}
+
+impl Inode {
+    fn new(kind: InodeKind) -> Self {
+        Inode {
+            kind,
+            links: 1,
+            size: 0,
+            direct: [0; DIRECT_BLOCKS],
+            indirect: 0,
+        }
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, FsError> {
+        let mut direct = [0; DIRECT_BLOCKS];
+        for (i, block) in direct.iter_mut().enumerate() {
+            *block = read_u32(data, 0x10 + i * 4);
+        }
+        let size = read_u64(data, 0x8);
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::Corrupt);
+        }
+        Ok(Inode {
+            kind: InodeKind::from_u16(read_u16(data, 0x0))?,
+            links: read_u16(data, 0x2),
+            size,
+            direct,
+            indirect: read_u32(data, 0x38),
+        })
+    }
+
+    fn serialize(&self, out: &mut [u8]) {
+        out[..INODE_SIZE].fill(0);
+        out[0x0..0x2].copy_from_slice(&(self.kind as u16).to_le_bytes());
+        out[0x2..0x4].copy_from_slice(&self.links.to_le_bytes());
+        out[0x8..0x10].copy_from_slice(&self.size.to_le_bytes());
+        for (i, block) in self.direct.iter().enumerate() {
+            write_u32(out, 0x10 + i * 4, *block);
+        }
+        write_u32(out, 0x38, self.indirect);
+    }
+}
+
+/*
+Directory entry, 64 bytes:
+
+    0x00 inode u32 (0 for an unused slot), 0x04 kind u8, 0x05 name length u8,
+    0x08 name [u8; 56]
+*/
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub kind: InodeKind,
+    pub name: String,
+}
+
+impl DirEntry {
+    fn new(inode: u32, kind: InodeKind, name: &str) -> Self {
+        DirEntry {
+            inode,
+            kind,
+            name: name.to_owned(),
+        }
+    }
+
+    fn parse(data: &[u8]) -> Result<Option<Self>, FsError> {
+        let inode = read_u32(data, 0x0);
+        if inode == 0 {
+            return Ok(None);
+        }
+        let name_len = data[0x5] as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(FsError::Corrupt);
+        }
+        let name = str::from_utf8(&data[0x8..0x8 + name_len]).map_err(|_| FsError::Corrupt)?;
+        let kind = InodeKind::from_u16(data[0x4] as u16)?;
+        if kind == InodeKind::Free {
+            return Err(FsError::Corrupt);
+        }
+        Ok(Some(DirEntry::new(inode, kind, name)))
+    }
+
+    fn serialize(&self) -> [u8; DIR_ENTRY_SIZE] {
+        let mut out = [0; DIR_ENTRY_SIZE];
+        write_u32(&mut out, 0x0, self.inode);
+        out[0x4] = self.kind as u8;
+        out[0x5] = self.name.len() as u8;
+        out[0x8..0x8 + self.name.len()].copy_from_slice(self.name.as_bytes());
+        out
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidName);
+    }
+    if name.contains('/') || name.contains('\0') || name.len() > MAX_NAME_LEN {
+        return Err(FsError::InvalidName);
+    }
+    Ok(())
+}
+
+// Splits "/a/b/c" into ("/a/b", "c")
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => ("", path),
+    }
+}
 
-#[derive(Debug)]
-pub struct SimpleFS {
-    files: Vec<SimpleFile>,
+// Writable filesystem with nested directories. Paths are `/` separated and
+// always start at the root, a leading `/` is optional. Every directory holds
+// `.` and `..` entries so walking a path needs no special cases.
+// The free bitmap is kept in memory and written through, inodes and data
+// always go to the disk.
+pub struct InodeFS {
+    sb: SuperBlock,
+    bitmap: Vec<u8>,
+    // no free block below this one
+    next_free: u32,
 }
 
-const FS_MAGIC: u32 = 0x34127777;
+impl fmt::Debug for InodeFS {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InodeFS")
+            .field("superblock", &self.sb)
+            .field("free_blocks", &self.free_blocks())
+            .finish()
+    }
+}
+
+impl InodeFS {
+    pub fn mount(disk: &mut Disk) -> Result<Self, FsError> {
+        let sb = SuperBlock::parse(&disk.read_block(0)?)?;
+
+        let mut bitmap = Vec::with_capacity(sb.bitmap_blocks as usize * BLOCK_SIZE);
+        for i in 0..sb.bitmap_blocks {
+            bitmap.extend_from_slice(&disk.read_block(sb.bitmap_start + i)?);
+        }
+
+        let fs = InodeFS {
+            next_free: sb.data_start,
+            sb,
+            bitmap,
+        };
+        if fs.read_inode(disk, ROOT_INODE)?.kind != InodeKind::Dir {
+            return Err(FsError::Corrupt);
+        }
+        Ok(fs)
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        (self.sb.data_start..self.sb.total_blocks)
+            .filter(|b| !self.block_used(*b))
+            .count()
+    }
 
-impl SimpleFS {
-    pub fn new(data: Vec<u8>) -> Self {
-        assert!(data.len() == SECTOR_SIZE);
+    // Entries of the directory at `path`, without `.` and `..`
+    pub fn list_dir(&self, disk: &mut Disk, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.resolve_dir(disk, path)?;
+        Ok(self
+            .dir_entries(disk, dir)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .collect())
+    }
 
-        let mut files = vec![];
+    pub fn read_file(&self, disk: &mut Disk, path: &str) -> Result<Vec<u8>, FsError> {
+        let (_, inode) = self.lookup_file(disk, path)?;
+        self.read_data(disk, &inode, 0, inode.size as usize)
+    }
 
-        let magic: u32 =
-            u32::from_le_bytes(data[0..4].try_into().expect("Couldn't get offset [1]"));
+    // Creates an empty file and returns its inode number
+    pub fn create(&mut self, disk: &mut Disk, path: &str) -> Result<u32, FsError> {
+        let (dir, name) = self.resolve_parent(disk, path)?;
+        self.new_inode(disk, dir, name, InodeKind::File)
+    }
 
-        assert!(magic == FS_MAGIC);
+    pub fn mkdir(&mut self, disk: &mut Disk, path: &str) -> Result<u32, FsError> {
+        let (parent, name) = self.resolve_parent(disk, path)?;
+        let ino = self.new_inode(disk, parent, name, InodeKind::Dir)?;
 
-        let num_files =
-            u32::from_le_bytes(data[4..8].try_into().expect("Couldn't get offset [1]")) as usize;
+        let mut result = self.add_entry(disk, ino, &DirEntry::new(ino, InodeKind::Dir, "."));
+        if result.is_ok() {
+            result = self.add_entry(disk, ino, &DirEntry::new(parent, InodeKind::Dir, ".."));
+        }
+        if let Err(e) = result {
+            // best effort, the directory is unusable without both entries
+            let _ = self.delete(disk, path);
+            return Err(e);
+        }
+        Ok(ino)
+    }
+
+    pub fn append(&mut self, disk: &mut Disk, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let (ino, mut inode) = self.lookup_file(disk, path)?;
+        let offset = inode.size;
+        self.write_data(disk, ino, &mut inode, offset, data)
+    }
+
+    // Shrinks or grows the file to `size` bytes, growing leaves a hole
+    pub fn truncate(&mut self, disk: &mut Disk, path: &str, size: u64) -> Result<(), FsError> {
+        let (ino, _) = self.lookup_file(disk, path)?;
+        self.truncate_inode(disk, ino, size)
+    }
+
+    // Metadata of inode `ino`
+    pub fn stat(&self, disk: &mut Disk, ino: u32) -> Result<Inode, FsError> {
+        self.read_inode(disk, ino)
+    }
 
-        let mut str_start = 8;
-        let mut str_end = str_start;
-        let mut file_names = vec![];
-        for _ in 0..num_files {
-            while data[str_end] != 0 {
-                str_end += 1;
+    // Reads up to `len` bytes at `offset` of a file, less at the end of it
+    pub fn read_at(
+        &self,
+        disk: &mut Disk,
+        ino: u32,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, FsError> {
+        let inode = self.file_inode(disk, ino)?;
+        if offset >= inode.size {
+            return Ok(vec![]);
+        }
+        self.read_data(disk, &inode, offset as usize, len)
+    }
+
+    pub fn write_at(
+        &mut self,
+        disk: &mut Disk,
+        ino: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        let mut inode = self.file_inode(disk, ino)?;
+        self.write_data(disk, ino, &mut inode, offset, data)
+    }
+
+    pub fn truncate_inode(&mut self, disk: &mut Disk, ino: u32, size: u64) -> Result<(), FsError> {
+        let mut inode = self.file_inode(disk, ino)?;
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+
+        if size < inode.size {
+            let first_unused = (size as usize).div_ceil(BLOCK_SIZE);
+            self.free_blocks_from(disk, &mut inode, first_unused)?;
+
+            // the rest of the last block must read as zeroes if the file grows again
+            let tail = size as usize % BLOCK_SIZE;
+            if tail != 0 {
+                let block = self.block_of(disk, &inode, size as usize / BLOCK_SIZE)?;
+                if block != 0 {
+                    let mut data = disk.read_block(block)?;
+                    data[tail..].fill(0);
+                    disk.write_block(block, &data)?;
+                }
             }
-            str_end += 1; // str_end is now byte after null byte
-            let file_name = str::from_utf8(
-                data[str_start..str_end - 1]
-                    .try_into()
-                    .expect("Couldn't get offset [1]"),
-            )
-            .expect("couldnt parse string");
-            str_start = str_end;
-
-            file_names.push(file_name);
-        }
-        // round up to nearest multiple of 8
-        let mut files_info = vec![];
-        let file_info_start = (str_end + 7) & !7usize;
-        for i in 0..num_files {
-            let offset = u64::from_le_bytes(
-                data[file_info_start + (i * 0x10)..file_info_start + (i * 0x10) + 8]
-                    .try_into()
-                    .expect("Couldn't get offset [1]"),
-            ) as usize;
-            let size = u64::from_le_bytes(
-                data[file_info_start + (i * 0x10) + 8..file_info_start + (i * 0x10) + 0x10]
-                    .try_into()
-                    .expect("Couldn't get offset [1]"),
-            ) as usize;
-            files_info.push((offset, size));
-        }
-
-        for i in 0..num_files {
-            files.push(SimpleFile {
-                offset: files_info[i].0,
-                size: files_info[i].1,
-                name: file_names[i].to_owned(),
-            })
-        }
-
-        SimpleFS { files }
-    }
-
-    pub fn load_file(
+        }
+        inode.size = size;
+        self.write_inode(disk, ino, &inode)
+    }
+
+    // Removes a file or an empty directory
+    pub fn delete(&mut self, disk: &mut Disk, path: &str) -> Result<(), FsError> {
+        let (dir, index, ino) = self.lookup(disk, path)?;
+        let mut inode = self.read_inode(disk, ino)?;
+        if inode.kind == InodeKind::Dir && !self.dir_is_empty(disk, ino)? {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        self.write_entry(disk, dir, index, &[0; DIR_ENTRY_SIZE])?;
+
+        inode.links = inode.links.saturating_sub(1);
+        if inode.links == 0 || inode.kind == InodeKind::Dir {
+            self.free_blocks_from(disk, &mut inode, 0)?;
+            inode = Inode::new(InodeKind::Free);
+        }
+        self.write_inode(disk, ino, &inode)
+    }
+
+    // Moves `old` to `new`, which may be in another directory but must not exist
+    pub fn rename(&mut self, disk: &mut Disk, old: &str, new: &str) -> Result<(), FsError> {
+        let (old_dir, old_name) = self.resolve_parent(disk, old)?;
+        let (index, mut entry) = self
+            .find_entry(disk, old_dir, old_name)?
+            .ok_or(FsError::NotFound)?;
+        let (new_dir, new_name) = self.resolve_parent(disk, new)?;
+        if old_dir == new_dir && old_name == new_name {
+            return Ok(());
+        }
+        if self.find_entry(disk, new_dir, new_name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        if old_dir == new_dir {
+            entry.name = new_name.to_owned();
+            return self.write_entry(disk, old_dir, index, &entry.serialize());
+        }
+
+        if entry.kind == InodeKind::Dir {
+            // a directory can't be moved below itself
+            let mut ancestor = new_dir;
+            while ancestor != ROOT_INODE {
+                if ancestor == entry.inode {
+                    return Err(FsError::InvalidPath);
+                }
+                ancestor = self.parent_of(disk, ancestor)?;
+            }
+        }
+
+        entry.name = new_name.to_owned();
+        self.add_entry(disk, new_dir, &entry)?;
+        self.write_entry(disk, old_dir, index, &[0; DIR_ENTRY_SIZE])?;
+
+        if entry.kind == InodeKind::Dir {
+            let (dotdot, _) = self
+                .find_entry(disk, entry.inode, "..")?
+                .ok_or(FsError::Corrupt)?;
+            let parent = DirEntry::new(new_dir, InodeKind::Dir, "..");
+            self.write_entry(disk, entry.inode, dotdot, &parent.serialize())?;
+        }
+        Ok(())
+    }
+
+    // Inode number of whatever `path` names, following `.` and `..`
+    pub fn resolve(&self, disk: &mut Disk, path: &str) -> Result<u32, FsError> {
+        let mut ino = ROOT_INODE;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            if self.read_inode(disk, ino)?.kind != InodeKind::Dir {
+                return Err(FsError::NotADirectory);
+            }
+            ino = self
+                .find_entry(disk, ino, part)?
+                .ok_or(FsError::NotFound)?
+                .1
+                .inode;
+        }
+        Ok(ino)
+    }
+
+    fn resolve_dir(&self, disk: &mut Disk, path: &str) -> Result<u32, FsError> {
+        let ino = self.resolve(disk, path)?;
+        if self.read_inode(disk, ino)?.kind != InodeKind::Dir {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(ino)
+    }
+
+    // Directory that would hold `path` and the name of the last component
+    fn resolve_parent<'p>(
         &self,
+        disk: &mut Disk,
+        path: &'p str,
+    ) -> Result<(u32, &'p str), FsError> {
+        let (dir, name) = split_path(path);
+        check_name(name)?;
+        Ok((self.resolve_dir(disk, dir)?, name))
+    }
+
+    // Directory, slot in it and inode number of an existing `path`
+    fn lookup(&self, disk: &mut Disk, path: &str) -> Result<(u32, usize, u32), FsError> {
+        let (dir, name) = self.resolve_parent(disk, path)?;
+        let (index, entry) = self.find_entry(disk, dir, name)?.ok_or(FsError::NotFound)?;
+        Ok((dir, index, entry.inode))
+    }
+
+    fn lookup_file(&self, disk: &mut Disk, path: &str) -> Result<(u32, Inode), FsError> {
+        let (_, _, ino) = self.lookup(disk, path)?;
+        Ok((ino, self.file_inode(disk, ino)?))
+    }
+
+    fn file_inode(&self, disk: &mut Disk, ino: u32) -> Result<Inode, FsError> {
+        let inode = self.read_inode(disk, ino)?;
+        if inode.kind != InodeKind::File {
+            return Err(FsError::NotAFile);
+        }
+        Ok(inode)
+    }
+
+    fn parent_of(&self, disk: &mut Disk, dir: u32) -> Result<u32, FsError> {
+        Ok(self
+            .find_entry(disk, dir, "..")?
+            .ok_or(FsError::Corrupt)?
+            .1
+            .inode)
+    }
+
+    fn dir_is_empty(&self, disk: &mut Disk, dir: u32) -> Result<bool, FsError> {
+        Ok(self
+            .dir_entries(disk, dir)?
+            .iter()
+            .all(|(_, entry)| entry.name == "." || entry.name == ".."))
+    }
+
+    // Allocates an inode of `kind` and links it into `dir` as `name`
+    fn new_inode(
+        &mut self,
+        disk: &mut Disk,
+        dir: u32,
         name: &str,
-        port: &mut HbaPort,
-        heap_phys_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-    ) -> Option<Vec<u8>> {
-        for file in self.files.iter() {
-            if file.name == name {
-                let startl = (file.offset / SECTOR_SIZE) as u32;
-                let starth = ((file.offset / SECTOR_SIZE) >> 32) as u32;
-                let mut sectors = file.size / SECTOR_SIZE;
-                if file.size % SECTOR_SIZE != 0 {
-                    sectors += 1;
+        kind: InodeKind,
+    ) -> Result<u32, FsError> {
+        if self.find_entry(disk, dir, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let ino = self.alloc_inode(disk)?;
+        self.write_inode(disk, ino, &Inode::new(kind))?;
+        if let Err(e) = self.add_entry(disk, dir, &DirEntry::new(ino, kind, name)) {
+            self.write_inode(disk, ino, &Inode::new(InodeKind::Free))?;
+            return Err(e);
+        }
+        Ok(ino)
+    }
+
+    fn check_block(&self, block: u32) -> Result<u32, FsError> {
+        if block < self.sb.data_start || block >= self.sb.total_blocks {
+            return Err(FsError::Corrupt);
+        }
+        Ok(block)
+    }
+
+    fn block_used(&self, block: u32) -> bool {
+        self.bitmap[block as usize / 8] & (1 << (block % 8)) != 0
+    }
+
+    fn set_block_used(&mut self, disk: &mut Disk, block: u32, used: bool) -> Result<(), FsError> {
+        if used {
+            self.bitmap[block as usize / 8] |= 1 << (block % 8);
+        } else {
+            self.bitmap[block as usize / 8] &= !(1 << (block % 8));
+        }
+        let bitmap_block = block / BITS_PER_BLOCK;
+        let start = bitmap_block as usize * BLOCK_SIZE;
+        disk.write_block(
+            self.sb.bitmap_start + bitmap_block,
+            &self.bitmap[start..start + BLOCK_SIZE],
+        )
+    }
+
+    fn alloc_block(&mut self, disk: &mut Disk) -> Result<u32, FsError> {
+        let block = (self.next_free..self.sb.total_blocks)
+            .find(|b| !self.block_used(*b))
+            .ok_or(FsError::NoSpace)?;
+        self.set_block_used(disk, block, true)?;
+        self.next_free = block + 1;
+        Ok(block)
+    }
+
+    fn free_block(&mut self, disk: &mut Disk, block: u32) -> Result<(), FsError> {
+        self.set_block_used(disk, self.check_block(block)?, false)?;
+        self.next_free = self.next_free.min(block);
+        Ok(())
+    }
+
+    // Block on disk and byte offset in it that hold inode `ino`
+    fn inode_location(&self, ino: u32) -> Result<(u32, usize), FsError> {
+        if ino == 0 || ino >= self.sb.inode_count {
+            return Err(FsError::Corrupt);
+        }
+        let block = self.sb.inode_table_start + ino / INODES_PER_BLOCK as u32;
+        let offset = (ino as usize % INODES_PER_BLOCK) * INODE_SIZE;
+        Ok((block, offset))
+    }
+
+    fn read_inode(&self, disk: &mut Disk, ino: u32) -> Result<Inode, FsError> {
+        let (block, offset) = self.inode_location(ino)?;
+        let data = disk.read_block(block)?;
+        Inode::parse(&data[offset..offset + INODE_SIZE])
+    }
+
+    fn write_inode(&self, disk: &mut Disk, ino: u32, inode: &Inode) -> Result<(), FsError> {
+        let (block, offset) = self.inode_location(ino)?;
+        let mut data = disk.read_block(block)?;
+        inode.serialize(&mut data[offset..offset + INODE_SIZE]);
+        disk.write_block(block, &data)
+    }
+
+    fn alloc_inode(&self, disk: &mut Disk) -> Result<u32, FsError> {
+        for table_block in 0..self.sb.inode_table_blocks {
+            let data = disk.read_block(self.sb.inode_table_start + table_block)?;
+            for i in 0..INODES_PER_BLOCK {
+                let ino = table_block * INODES_PER_BLOCK as u32 + i as u32;
+                if ino == 0 || ino >= self.sb.inode_count {
+                    continue;
+                }
+                if read_u16(&data, i * INODE_SIZE) == InodeKind::Free as u16 {
+                    return Ok(ino);
                 }
-                return Some(
-                    port.read(startl, starth, sectors, heap_phys_regions)
-                        .expect("read failed"),
-                );
             }
         }
-        None
+        Err(FsError::NoInodes)
+    }
+
+    // Disk block backing block `index` of the inode, 0 for a hole
+    fn block_of(&self, disk: &mut Disk, inode: &Inode, index: usize) -> Result<u32, FsError> {
+        let block = if index < DIRECT_BLOCKS {
+            inode.direct[index]
+        } else if index < MAX_FILE_BLOCKS {
+            if inode.indirect == 0 {
+                return Ok(0);
+            }
+            let table = disk.read_block(self.check_block(inode.indirect)?)?;
+            read_u32(&table, (index - DIRECT_BLOCKS) * 4)
+        } else {
+            return Err(FsError::FileTooLarge);
+        };
+        if block == 0 {
+            return Ok(0);
+        }
+        self.check_block(block)
+    }
+
+    // Like `block_of` but fills holes with freshly allocated blocks.
+    // The caller has to write the inode back.
+    fn block_of_or_alloc(
+        &mut self,
+        disk: &mut Disk,
+        inode: &mut Inode,
+        index: usize,
+    ) -> Result<(u32, bool), FsError> {
+        let existing = self.block_of(disk, inode, index)?;
+        if existing != 0 {
+            return Ok((existing, false));
+        }
+
+        if index < DIRECT_BLOCKS {
+            let block = self.alloc_block(disk)?;
+            inode.direct[index] = block;
+            return Ok((block, true));
+        }
+
+        // a new table only goes into the inode once it is on disk
+        let (indirect, mut table, fresh) = if inode.indirect == 0 {
+            (self.alloc_block(disk)?, vec![0; BLOCK_SIZE], true)
+        } else {
+            let indirect = self.check_block(inode.indirect)?;
+            (indirect, disk.read_block(indirect)?, false)
+        };
+        let block = match self.alloc_block(disk) {
+            Ok(block) => block,
+            Err(err) => {
+                if fresh {
+                    self.free_block(disk, indirect)?;
+                }
+                return Err(err);
+            }
+        };
+        write_u32(&mut table, (index - DIRECT_BLOCKS) * 4, block);
+        disk.write_block(indirect, &table)?;
+        inode.indirect = indirect;
+        Ok((block, true))
+    }
+
+    // Releases block `first` and everything after it
+    fn free_blocks_from(
+        &mut self,
+        disk: &mut Disk,
+        inode: &mut Inode,
+        first: usize,
+    ) -> Result<(), FsError> {
+        for index in first..DIRECT_BLOCKS {
+            if inode.direct[index] != 0 {
+                self.free_block(disk, inode.direct[index])?;
+                inode.direct[index] = 0;
+            }
+        }
+
+        if inode.indirect == 0 {
+            return Ok(());
+        }
+        let indirect = self.check_block(inode.indirect)?;
+        let mut table = disk.read_block(indirect)?;
+        let first = first.saturating_sub(DIRECT_BLOCKS);
+        for i in first..PTRS_PER_BLOCK {
+            let block = read_u32(&table, i * 4);
+            if block != 0 {
+                self.free_block(disk, block)?;
+                write_u32(&mut table, i * 4, 0);
+            }
+        }
+        if first == 0 {
+            self.free_block(disk, indirect)?;
+            inode.indirect = 0;
+        } else {
+            disk.write_block(indirect, &table)?;
+        }
+        Ok(())
+    }
+
+    fn read_data(
+        &self,
+        disk: &mut Disk,
+        inode: &Inode,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, FsError> {
+        let end = offset.saturating_add(len).min(inode.size as usize);
+        let mut out = Vec::with_capacity(end.saturating_sub(offset));
+        let mut pos = offset;
+        while pos < end {
+            let in_block = pos % BLOCK_SIZE;
+            let chunk = (BLOCK_SIZE - in_block).min(end - pos);
+            let block = self.block_of(disk, inode, pos / BLOCK_SIZE)?;
+            if block == 0 {
+                out.resize(out.len() + chunk, 0);
+            } else {
+                let data = disk.read_block(block)?;
+                out.extend_from_slice(&data[in_block..in_block + chunk]);
+            }
+            pos += chunk;
+        }
+        Ok(out)
+    }
+
+    // Writes `data` at `offset` and stores the updated inode
+    fn write_data(
+        &mut self,
+        disk: &mut Disk,
+        ino: u32,
+        inode: &mut Inode,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), FsError> {
+        match offset.checked_add(data.len() as u64) {
+            Some(end) if end <= MAX_FILE_SIZE => {}
+            _ => return Err(FsError::FileTooLarge),
+        }
+
+        let mut pos = offset as usize;
+        let mut written = 0;
+        let result = loop {
+            if written == data.len() {
+                break Ok(());
+            }
+            let in_block = pos % BLOCK_SIZE;
+            let chunk = (BLOCK_SIZE - in_block).min(data.len() - written);
+            let (block, fresh) = match self.block_of_or_alloc(disk, inode, pos / BLOCK_SIZE) {
+                Ok(b) => b,
+                Err(e) => break Err(e),
+            };
+
+            let mut buf = if fresh || chunk == BLOCK_SIZE {
+                vec![0; BLOCK_SIZE]
+            } else {
+                match disk.read_block(block) {
+                    Ok(b) => b,
+                    Err(e) => break Err(e),
+                }
+            };
+            buf[in_block..in_block + chunk].copy_from_slice(&data[written..written + chunk]);
+            if let Err(e) = disk.write_block(block, &buf) {
+                break Err(e);
+            }
+            pos += chunk;
+            written += chunk;
+        };
+
+        // keep whatever made it to the disk reachable even on failure
+        inode.size = inode.size.max(offset + written as u64);
+        self.write_inode(disk, ino, inode)?;
+        result
+    }
+
+    // Raw contents of a directory, a whole number of entries
+    fn dir_data(&self, disk: &mut Disk, dir: u32) -> Result<Vec<u8>, FsError> {
+        let inode = self.read_inode(disk, dir)?;
+        if inode.kind != InodeKind::Dir {
+            return Err(FsError::NotADirectory);
+        }
+        if !(inode.size as usize).is_multiple_of(DIR_ENTRY_SIZE) {
+            return Err(FsError::Corrupt);
+        }
+        self.read_data(disk, &inode, 0, inode.size as usize)
+    }
+
+    fn dir_entries(&self, disk: &mut Disk, dir: u32) -> Result<Vec<(usize, DirEntry)>, FsError> {
+        let data = self.dir_data(disk, dir)?;
+        let mut entries = vec![];
+        for (index, raw) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            if let Some(entry) = DirEntry::parse(raw)? {
+                entries.push((index, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(
+        &self,
+        disk: &mut Disk,
+        dir: u32,
+        name: &str,
+    ) -> Result<Option<(usize, DirEntry)>, FsError> {
+        Ok(self
+            .dir_entries(disk, dir)?
+            .into_iter()
+            .find(|(_, entry)| entry.name == name))
+    }
+
+    fn add_entry(&mut self, disk: &mut Disk, dir: u32, entry: &DirEntry) -> Result<(), FsError> {
+        let data = self.dir_data(disk, dir)?;
+        // reuse a removed slot before growing the directory
+        let index = data
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .position(|raw| read_u32(raw, 0) == 0)
+            .unwrap_or(data.len() / DIR_ENTRY_SIZE);
+        self.write_entry(disk, dir, index, &entry.serialize())
+    }
+
+    // Overwrites slot `index` of `dir` with a serialized entry
+    fn write_entry(
+        &mut self,
+        disk: &mut Disk,
+        dir: u32,
+        index: usize,
+        raw: &[u8; DIR_ENTRY_SIZE],
+    ) -> Result<(), FsError> {
+        let mut inode = self.read_inode(disk, dir)?;
+        self.write_data(disk, dir, &mut inode, (index * DIR_ENTRY_SIZE) as u64, raw)
+    }
+}
+
+// InodeFS on the boot disk, the root of the VFS
+pub struct DiskFS {
+    fs: InodeFS,
+    port: &'static mut HbaPort,
+}
+
+impl DiskFS {
+    pub fn new(fs: InodeFS, port: &'static mut HbaPort) -> Self {
+        DiskFS { fs, port }
+    }
+
+    // Runs `f` with the filesystem and a view of the disk it lives on
+    pub fn with_disk<T>(
+        &mut self,
+        f: impl FnOnce(&mut InodeFS, &mut Disk) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let mut disk = Disk::new(self.port);
+        f(&mut self.fs, &mut disk)
+    }
+}
+
+impl FileSystem for DiskFS {
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<NodeId, FsError> {
+        self.with_disk(|fs, disk| {
+            let ino = match fs.resolve(disk, path) {
+                Ok(ino) => ino,
+                Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                    fs.create(disk, path)?
+                }
+                Err(e) => return Err(e),
+            };
+            let kind = fs.stat(disk, ino)?.kind;
+            if kind == InodeKind::Dir && flags.contains(OpenFlags::WRITE) {
+                return Err(FsError::NotAFile);
+            }
+            if flags.contains(OpenFlags::TRUNCATE) {
+                fs.truncate_inode(disk, ino, 0)?;
+            }
+            Ok(ino)
+        })
+    }
+
+    fn read(&mut self, node: NodeId, offset: u64, len: usize) -> Result<Vec<u8>, FsError> {
+        self.with_disk(|fs, disk| fs.read_at(disk, node, offset, len))
+    }
+
+    fn write(&mut self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.with_disk(|fs, disk| fs.write_at(disk, node, offset, data))?;
+        Ok(data.len())
+    }
+
+    fn stat(&mut self, node: NodeId) -> Result<Stat, FsError> {
+        let inode = self.with_disk(|fs, disk| fs.stat(disk, node))?;
+        Ok(Stat {
+            node,
+            kind: inode.kind,
+            size: inode.size,
+        })
     }
 }
 
-#[derive(Debug)]
-struct SimpleFile {
-    offset: usize,
-    size: usize,
-    name: String,
+// Reads the whole file at `path` through the VFS
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let fs = kernel_data.fs.as_mut().ok_or(FsError::Io)?;
+    let node = fs.open(path, OpenFlags::READ)?;
+    let data = fs
+        .stat(node)
+        .and_then(|stat| fs.read(node, 0, stat.size as usize));
+    fs.close(node)?;
+    data
 }
@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+
+use syscall_defs::{O_APPEND, O_CREATE, O_READ, O_TRUNCATE, O_WRITE, SEEK_CUR, SEEK_END, SEEK_SET};
+
+use crate::fs::{FsError, InodeKind};
+
+pub const MAX_OPEN_FILES: usize = 32;
+
+// Identifies a file inside one filesystem, for InodeFS it is the inode number
+pub type NodeId = u32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub node: NodeId,
+    pub kind: InodeKind,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u64);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(O_READ);
+    pub const WRITE: OpenFlags = OpenFlags(O_WRITE);
+    pub const CREATE: OpenFlags = OpenFlags(O_CREATE);
+    pub const TRUNCATE: OpenFlags = OpenFlags(O_TRUNCATE);
+    pub const APPEND: OpenFlags = OpenFlags(O_APPEND);
+
+    const ALL: u64 = O_READ | O_WRITE | O_CREATE | O_TRUNCATE | O_APPEND;
+
+    // None if unknown bits are set
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        if bits & !Self::ALL != 0 {
+            return None;
+        }
+        Some(OpenFlags(bits))
+    }
+
+    pub fn contains(&self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+impl Whence {
+    pub fn from_u64(whence: u64) -> Option<Self> {
+        match whence {
+            SEEK_SET => Some(Whence::Set),
+            SEEK_CUR => Some(Whence::Current),
+            SEEK_END => Some(Whence::End),
+            _ => None,
+        }
+    }
+}
+
+// What every filesystem provides to the rest of the kernel. Offsets are kept
+// by the caller, a filesystem only sees positioned reads and writes.
+pub trait FileSystem: Send {
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<NodeId, FsError>;
+
+    // Returns less than `len` bytes at the end of the file
+    fn read(&mut self, node: NodeId, offset: u64, len: usize) -> Result<Vec<u8>, FsError>;
+
+    // Returns how many bytes were written
+    fn write(&mut self, node: NodeId, offset: u64, data: &[u8]) -> Result<usize, FsError>;
+
+    fn stat(&mut self, node: NodeId) -> Result<Stat, FsError>;
+
+    // Called whenever a descriptor is closed, forked children close the ones
+    // they inherited as well
+    fn close(&mut self, _node: NodeId) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenFile {
+    pub node: NodeId,
+    pub offset: u64,
+    pub flags: OpenFlags,
+}
+
+// Per process table of open files, indexed by file descriptor
+#[derive(Debug, Clone)]
+pub struct FileTable {
+    files: [Option<OpenFile>; MAX_OPEN_FILES],
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        const EMPTY: Option<OpenFile> = None;
+        FileTable {
+            files: [EMPTY; MAX_OPEN_FILES],
+        }
+    }
+
+    // Stores `file` in the lowest free descriptor
+    pub fn insert(&mut self, file: OpenFile) -> Option<usize> {
+        let fd = self.files.iter().position(|f| f.is_none())?;
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Option<&mut OpenFile> {
+        self.files.get_mut(fd)?.as_mut()
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<OpenFile> {
+        self.files.get_mut(fd)?.take()
+    }
+}
@@ -2,38 +2,45 @@ use crate::alloc::boxed::Box;
 use crate::alloc::vec::Vec;
 use crate::apic::{check_apic, get_apic_base, ident_map_apic_page};
 use crate::bootloader_structs::BootInfo;
+use crate::cpu::{check_nx, enable_nx, init_pat};
 use crate::elf::{fix_relocatable_addrs, get_loadable_prog_header_entries, ProgHeaderEntry};
 use crate::init::phase2::phase2_init;
-use crate::memory::frame_allocator::LinkedListFrameAllocator;
-use crate::memory::heap::{
-    fix_heap_after_remap, init_heap_phase1, init_heap_phase2, translate_box, translate_box_vec,
-};
+use crate::memory::frame_allocator::FrameAllocator;
+use crate::memory::heap::{fix_heap_after_remap, init_heap, translate_box, translate_box_vec};
 use crate::memory::mappings::{
-    ident_map_vga_buf, map_elf_at_current_mapping, map_elf_at_new_base, map_heap,
-    unmap_elf_at_original_mapping, ELF_NEW_BASE, ELF_OLD_BASE,
+    ident_map_vga_buf, map_elf_at_current_mapping, map_elf_at_new_base, map_frame_table, map_heap,
+    map_physical_memory, unmap_elf_at_original_mapping, ELF_NEW_BASE, ELF_OLD_BASE,
 };
 use crate::memory::page_table::{PhysPage4KiB, PML4};
+use crate::memory::phys_map::enable_phys_map;
 use crate::memory::stack::{create_new_stack_and_map, KERN_STACK_TOP};
 use crate::println;
 use core::arch::asm;
 
 pub fn phase1_init(boot_info: &BootInfo) -> ! {
-    let mut frame_allocator = LinkedListFrameAllocator::init(boot_info);
+    let mut frame_allocator = FrameAllocator::init(boot_info);
 
-    let (heap_phys, num_pages_1) = init_heap_phase1(&mut frame_allocator);
-    let mut heap_phys_regions = init_heap_phase2(&mut frame_allocator, num_pages_1);
-    heap_phys_regions.push((heap_phys, num_pages_1));
+    let heap_phys_regions = init_heap(&mut frame_allocator);
 
     unsafe {
-        let pml4 = PML4::new(None);
+        if !check_nx() {
+            panic!("NX NOT AVALIBLE");
+        }
+        // the new page table uses both
+        enable_nx();
+        init_pat();
+
+        let pml4 = PML4::new();
         // map in stack, ELF regions twice, pagetable recursively
 
         let stack_phys = create_new_stack_and_map(&mut frame_allocator, pml4);
 
         map_heap(&heap_phys_regions, pml4);
+        map_frame_table(&frame_allocator, pml4);
+        map_physical_memory(pml4, frame_allocator.phys_limit());
         map_elf_at_current_mapping(boot_info, pml4);
         map_elf_at_new_base(boot_info, pml4);
-        ident_map_vga_buf(pml4, None);
+        ident_map_vga_buf(pml4);
 
         if check_apic() {
             println!("APIC AVALIBLE");
@@ -49,7 +56,7 @@ pub fn phase1_init(boot_info: &BootInfo) -> ! {
         // NEED TO TRANSLATE ALL HEAP ADDRESSES TO NEW HEAP LOCATION
         // We have the pagetable on heap
 
-        let frame_alloc_boxed: Box<LinkedListFrameAllocator> = Box::new(frame_allocator);
+        let frame_alloc_boxed: Box<FrameAllocator> = Box::new(frame_allocator);
         // this is invalid in the current context
         let frame_alloc_boxed = translate_box(&heap_phys_regions, frame_alloc_boxed);
 
@@ -83,7 +90,12 @@ pub fn phase1_init(boot_info: &BootInfo) -> ! {
             in("r8") stack_phys
         );
     }
-    loop {}
+    // the jump to phase 2 doesn't come back
+    loop {
+        unsafe {
+            asm!("cli", "hlt");
+        }
+    }
 }
 
 /// # Safety
@@ -92,16 +104,18 @@ pub fn phase1_init(boot_info: &BootInfo) -> ! {
 /// RDI, RSI, RDX, RCX, R8, R9
 #[no_mangle]
 pub unsafe extern "sysv64" fn phase_2_transition(
-    pml4: &mut PML4,
+    pml4: &'static mut PML4,
     heap_phys_regions: *mut Vec<(&'static PhysPage4KiB, usize)>,
-    frame_alloc: *mut LinkedListFrameAllocator,
+    frame_alloc: *mut FrameAllocator,
     prog_header_entries: *mut Vec<ProgHeaderEntry>,
     stack_phys: *const PhysPage4KiB,
 ) -> ! {
+    enable_phys_map();
     println!("Entering Phase 2!");
 
     let frame_alloc = Box::from_raw(frame_alloc);
-    let frame_alloc = Box::into_inner(frame_alloc);
+    let mut frame_alloc = Box::into_inner(frame_alloc);
+    frame_alloc.relocate_frame_table();
 
     let heap_phys_regions = Box::from_raw(heap_phys_regions);
     let heap_phys_regions: Vec<(&'static PhysPage4KiB, usize)> = Box::into_inner(heap_phys_regions);
@@ -109,15 +123,9 @@ pub unsafe extern "sysv64" fn phase_2_transition(
     let prog_header_entries = Box::from_raw(prog_header_entries);
     let prog_header_entries: Vec<ProgHeaderEntry> = Box::into_inner(prog_header_entries);
 
-    unmap_elf_at_original_mapping(&prog_header_entries, pml4, &heap_phys_regions);
+    unmap_elf_at_original_mapping(&prog_header_entries, pml4);
 
     fix_heap_after_remap(&heap_phys_regions);
 
-    phase2_init(
-        pml4,
-        frame_alloc,
-        heap_phys_regions,
-        prog_header_entries,
-        stack_phys,
-    )
+    phase2_init(pml4, frame_alloc, prog_header_entries, &*stack_phys)
 }
@@ -1,16 +1,26 @@
+use crate::acpi::{self, init_acpi};
 use crate::ahci::HbaMem;
+use crate::alloc::boxed::Box;
 use crate::alloc::vec::Vec;
 
+use crate::apic::ioapic::{init_ioapics, IOAPIC_DEFAULT_BASE};
+use crate::apic::{
+    disable_pic, enable_apic, get_apic_base, get_apic_id, set_apic_base, set_apic_tpr,
+    start_apic_timer, APIC_TIMER_VECTOR,
+};
 use crate::elf::ProgHeaderEntry;
-use crate::elf_loader::ElfLoader;
-use crate::memory::frame_allocator::LinkedListFrameAllocator;
-use crate::memory::heap::{heap_sanity_check, print_heap};
-use crate::memory::mappings::map_kernel_elf_into_user;
-use crate::memory::page_table::{PhysPage4KiB, PML4};
-use crate::memory::stack::create_new_user_stack_and_map;
+use crate::fs::disk::Disk;
+use crate::fs::{DiskFS, InodeFS};
+use crate::interrupts::interrupt_handlers::apic_timer_handler;
+use crate::interrupts::irq::register_irq;
+use crate::kernel_data::{KernelMemory, KERNEL_DATA, KERNEL_MEMORY};
+use crate::memory::frame_allocator::FrameAllocator;
+use crate::memory::heap::{enable_heap_growth, heap_sanity_check, print_heap};
+use crate::memory::page_table::{PageFlags, PhysPage4KiB, PML4};
 use crate::println;
+use crate::process::{create_process, scheduler};
 use crate::tss::*;
-use crate::user_mode::{enable_syscalls, enter_user_mode};
+use crate::user_mode::enable_syscalls;
 use crate::{ahci, gdt::*, pci};
 use crate::{fs, interrupts::*};
 
@@ -19,11 +29,10 @@ use crate::{fs, interrupts::*};
 // The old elf loadable regions that were in low memory are unmapped
 // It is trivial now to map pages, allocate pages, and allocate memory on heap
 pub fn phase2_init(
-    pml4: &mut PML4,
-    mut frame_alloc: LinkedListFrameAllocator,
-    heap_phys_regions: Vec<(&'static PhysPage4KiB, usize)>,
+    pml4: &'static mut PML4,
+    mut frame_alloc: FrameAllocator,
     prog_header_entries: Vec<ProgHeaderEntry>,
-    stack_phys: *const PhysPage4KiB,
+    stack_phys: &'static PhysPage4KiB,
 ) -> ! {
     // memory diagnostics
     println!("frame alloc has {:#x} free pages", frame_alloc.frame_count);
@@ -40,9 +49,13 @@ pub fn phase2_init(
     let mut gdt = GDT::create_gdt_on_heap(tss_hi, tss_lo);
     GDT::setup_gdt(&mut gdt);
 
+    // the scheduler updates rsp0 on every switch
+    KERNEL_DATA.lock().tss = Some(tss);
+
     // create new idt and load it
     let mut idt = IDT::create_idt_on_heap();
     IDT::setup_idt(&mut idt);
+    register_irq(APIC_TIMER_VECTOR, apic_timer_handler).expect("Couldn't register the timer");
 
     // unsafe {
     //     asm!("int3");
@@ -60,10 +73,33 @@ pub fn phase2_init(
     }
 
     let abar: &'static mut HbaMem = unsafe {
-        pml4.map_frame_4k(abar, abar, true, true, Some(&heap_phys_regions));
+        pml4.map_frame_4k(
+            abar,
+            abar,
+            PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::UNCACHED,
+        );
         &mut *(abar as *mut HbaMem) as &'static mut HbaMem
     };
 
+    // The MADT says where the I/O APICs are and how the ISA IRQs are wired,
+    // without it assume the usual single I/O APIC with the IRQs wired straight
+    // through. Their inputs stay masked until routed.
+    unsafe {
+        init_acpi(pml4);
+        let apic_id = get_apic_id(get_apic_base());
+        match acpi::madt() {
+            Some(madt) => {
+                let ioapics: Vec<(usize, u32)> = madt
+                    .ioapics
+                    .iter()
+                    .map(|ioapic| (ioapic.address, ioapic.gsi_base))
+                    .collect();
+                init_ioapics(&ioapics, madt.overrides, apic_id, pml4);
+            }
+            None => init_ioapics(&[(IOAPIC_DEFAULT_BASE, 0)], Vec::new(), apic_id, pml4),
+        }
+    }
+
     let mut sata_ports = Vec::new();
     for i in abar.implemented_ports() {
         if ahci::check_type(&abar.ports[i]) == ahci::AhciDevType::AhciDevSata {
@@ -74,43 +110,52 @@ pub fn phase2_init(
     // Only one connected drive expected
     assert_eq!(sata_ports.len(), 1);
     let sata_port_ind = sata_ports[0];
-    let _port_setup = abar.ports[sata_port_ind].port_rebase(&heap_phys_regions);
-
-    let data = abar.ports[sata_port_ind]
-        .read(0, 0, 1, &heap_phys_regions)
-        .expect("read failed");
-
-    let fs = fs::SimpleFS::new(data);
-    println!("{:#?}", fs);
-
-    let file_data = fs
-        .load_file("init", &mut abar.ports[sata_port_ind], &heap_phys_regions)
-        .expect("no init file");
+    let disk = &mut abar.ports[sata_port_ind];
+    // the port keeps using these for as long as the kernel runs
+    disk.port_rebase(&mut frame_alloc);
+
+    let fs = {
+        let mut disk = Disk::new(disk);
+        let fs = InodeFS::mount(&mut disk).expect("couldn't mount filesystem");
+        println!("{:#?}", fs);
+        let root = fs.list_dir(&mut disk, "/").expect("couldn't list root");
+        let names: Vec<&str> = root.iter().map(|entry| entry.name.as_str()).collect();
+        println!("Files: {:?}", names);
+        fs
+    };
 
-    let (user_pml4, entry_point) = ElfLoader::load(
-        file_data,
-        &mut frame_alloc,
+    {
+        let mut kernel_data = KERNEL_DATA.lock();
+        kernel_data.fs = Some(Box::new(DiskFS::new(fs, disk)));
+    }
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        frame_alloc,
         pml4,
-        &heap_phys_regions,
+        prog_header_entries,
         stack_phys,
-    );
-    map_kernel_elf_into_user(&prog_header_entries, user_pml4, &heap_phys_regions);
-    // TODO put this in above func
+    });
+    unsafe {
+        enable_heap_growth(KERNEL_MEMORY.lock().as_mut().unwrap());
+    }
 
     enable_syscalls();
-    create_new_user_stack_and_map(&mut frame_alloc, pml4, user_pml4, &heap_phys_regions);
-    enter_user_mode(entry_point, user_pml4, &heap_phys_regions);
 
-    // let apic_base = get_apic_base();
-    // set_apic_base(apic_base);
-    // unsafe {
-    //     enable_apic(apic_base);
-    //     set_apic_tpr(apic_base, 0);
-    // }
-    // disable_pic();
-    // enable_hardware_interrupts();
-    // start_apic_timer(apic_base);
+    let file_data = fs::read_file("init").expect("no init file");
+    let pid = create_process(file_data).expect("init is not a valid ELF");
+    println!("Spawned init with pid {}", pid);
+
+    // The timer drives the scheduler. Interrupts stay disabled in the kernel,
+    // they get enabled by the rflags of the first process we switch to.
+    let apic_base = get_apic_base();
+    unsafe {
+        set_apic_base(apic_base);
+        enable_apic(apic_base);
+        set_apic_tpr(apic_base, 0);
+    }
+    disable_pic();
+    unsafe {
+        start_apic_timer(apic_base);
+    }
 
-    // println!("We didn't die! :)");
-    // loop {}
+    scheduler::start()
 }
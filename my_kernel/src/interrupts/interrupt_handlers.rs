@@ -1,30 +1,205 @@
-use crate::apic::apic_end_of_interrupt;
+use core::fmt;
+
+use syscall_defs::EXIT_KILLED;
+
+use crate::cpu::read_cr2;
 use crate::interrupts::*;
-use crate::{print, println};
+use crate::kernel_data::KERNEL_DATA;
+use crate::println;
+use crate::process::scheduler::schedule;
+use crate::process::{exit_current_process, handle_page_fault};
+use crate::user_mode::load_kernel_page_table;
+
+// Bits of the error code pushed for a page fault
+struct PageFaultError(u64);
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let present = if self.0 & 1 != 0 {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        let access = if self.0 & (1 << 4) != 0 {
+            "instruction fetch"
+        } else if self.0 & (1 << 1) != 0 {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if self.0 & (1 << 2) != 0 {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{} on {} {}", present, mode, access)?;
+        if self.0 & (1 << 3) != 0 {
+            write!(f, ", reserved bit set")?;
+        }
+        Ok(())
+    }
+}
+
+// Ends the user process that raised an exception, it is never returned to.
+// Its parent sees `EXIT_KILLED` from wait.
+fn kill_current_process(sf: &InterruptStackFrame, reason: fmt::Arguments) -> ! {
+    load_kernel_page_table();
+    let pid = KERNEL_DATA.lock().current;
+    println!("Killed process {:?}: {} at rip {:#x}", pid, reason, sf.rip);
+    exit_current_process(EXIT_KILLED)
+}
 
 pub extern "x86-interrupt" fn bp_handler(sf: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", sf);
-    loop {}
+    if sf.from_user_mode() {
+        kill_current_process(&sf, format_args!("breakpoint"));
+    }
+    panic!("EXCEPTION: BREAKPOINT\n{:#?}", sf);
 }
 
 pub extern "x86-interrupt" fn de_handler(sf: InterruptStackFrame) {
-    println!("EXCEPTION: DIVIDE\n{:#?}", sf);
-    loop {}
+    if sf.from_user_mode() {
+        kill_current_process(&sf, format_args!("divide error"));
+    }
+    panic!("EXCEPTION: DIVIDE\n{:#?}", sf);
 }
 
 pub extern "x86-interrupt" fn gp_handler(sf: InterruptStackFrame, error: u64) {
-    println!("EXCEPTION: GP\n{:#?} error: {}", sf, error);
-    loop {}
+    if sf.from_user_mode() {
+        kill_current_process(
+            &sf,
+            format_args!("general protection fault, error {:#x}", error),
+        );
+    }
+    panic!("EXCEPTION: GP\n{:#?} error: {}", sf, error);
 }
 
 pub extern "x86-interrupt" fn pf_handler(sf: InterruptStackFrame, error: u64) {
-    println!("EXCEPTION: PF\n{:#?} error: {:#b}", sf, error);
-    loop {}
+    let addr = read_cr2();
+    if sf.from_user_mode() {
+        // first touch of a reserved page or a write to a copy on write page
+        if handle_page_fault(addr, error & 1 != 0, error & (1 << 1) != 0) {
+            return;
+        }
+        kill_current_process(
+            &sf,
+            format_args!("page fault at {:#x}, {}", addr, PageFaultError(error)),
+        );
+    }
+    panic!(
+        "EXCEPTION: PF at {:#x}, {}\n{:#?} error: {:#b}",
+        addr,
+        PageFaultError(error),
+        sf,
+        error
+    );
+}
+
+// Kills the user process that raised the exception, in the kernel it is a bug
+fn unhandled_exception(sf: &InterruptStackFrame, name: &str, error: Option<u64>) -> ! {
+    if sf.from_user_mode() {
+        match error {
+            Some(error) => kill_current_process(sf, format_args!("{}, error {:#x}", name, error)),
+            None => kill_current_process(sf, format_args!("{}", name)),
+        }
+    }
+    match error {
+        Some(error) => panic!("EXCEPTION: {}\n{:#?} error: {:#x}", name, sf, error),
+        None => panic!("EXCEPTION: {}\n{:#?}", name, sf),
+    }
+}
+
+pub extern "x86-interrupt" fn db_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "debug", None);
+}
+
+// not caused by the interrupted code, so no process is blamed
+pub extern "x86-interrupt" fn nmi_handler(sf: InterruptStackFrame) {
+    panic!("EXCEPTION: NMI\n{:#?}", sf);
+}
+
+pub extern "x86-interrupt" fn of_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "overflow", None);
+}
+
+pub extern "x86-interrupt" fn br_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "bound range exceeded", None);
+}
+
+pub extern "x86-interrupt" fn ud_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "invalid opcode", None);
+}
+
+pub extern "x86-interrupt" fn nm_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "device not available", None);
+}
+
+// Runs on its own stack, see `DOUBLE_FAULT_IST_INDEX`. The error code is always 0.
+pub extern "x86-interrupt" fn df_handler(sf: InterruptStackFrame, error: u64) -> ! {
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?} error: {:#x}", sf, error);
+}
+
+pub extern "x86-interrupt" fn cso_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "coprocessor segment overrun", None);
+}
+
+pub extern "x86-interrupt" fn ts_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "invalid tss", Some(error));
+}
+
+pub extern "x86-interrupt" fn np_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "segment not present", Some(error));
+}
+
+pub extern "x86-interrupt" fn ss_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "stack segment fault", Some(error));
+}
+
+pub extern "x86-interrupt" fn mf_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "x87 floating point error", None);
+}
+
+pub extern "x86-interrupt" fn ac_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "alignment check", Some(error));
+}
+
+// the machine state can't be trusted anymore, whoever was running
+pub extern "x86-interrupt" fn mc_handler(sf: InterruptStackFrame) -> ! {
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", sf);
+}
+
+pub extern "x86-interrupt" fn xm_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "simd floating point error", None);
+}
+
+pub extern "x86-interrupt" fn ve_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "virtualization exception", None);
+}
+
+pub extern "x86-interrupt" fn cp_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "control protection", Some(error));
+}
+
+pub extern "x86-interrupt" fn hv_handler(sf: InterruptStackFrame) {
+    unhandled_exception(&sf, "hypervisor injection", None);
+}
+
+pub extern "x86-interrupt" fn vc_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "vmm communication", Some(error));
+}
+
+pub extern "x86-interrupt" fn sx_handler(sf: InterruptStackFrame, error: u64) {
+    unhandled_exception(&sf, "security exception", Some(error));
+}
+
+// Vectors 15, 22-27 and 31, the cpu never raises these
+pub extern "x86-interrupt" fn reserved_handler(sf: InterruptStackFrame) {
+    panic!("EXCEPTION: RESERVED VECTOR\n{:#?}", sf);
 }
 
-pub extern "x86-interrupt" fn apic_timer_handler(_sf: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        apic_end_of_interrupt(0xfee00000);
+// Registered for `APIC_TIMER_VECTOR`
+pub fn apic_timer_handler(frame: &mut TrapFrame) {
+    // the kernel itself is not preemptible, only switch away from user code
+    if frame.from_user_mode() {
+        schedule();
     }
 }
@@ -0,0 +1,76 @@
+use crate::apic::{apic_end_of_interrupt, get_apic_base};
+use crate::interrupts::{without_interrupts, TrapFrame};
+use crate::println;
+
+// Vectors below this are cpu exceptions
+pub const FIRST_IRQ_VECTOR: usize = 32;
+// where the local APIC delivers spurious interrupts, these must not get an EOI
+pub const SPURIOUS_VECTOR: usize = 0xff;
+
+const IRQ_VECTORS: usize = 256 - FIRST_IRQ_VECTOR;
+// devices sharing a vector each register their own handler
+const MAX_SHARED_HANDLERS: usize = 4;
+
+// Runs with interrupts disabled and the kernel page table loaded. It may
+// switch to another process, in which case the handlers after it on the same
+// vector run once the interrupted process is switched back to.
+pub type IrqHandler = fn(&mut TrapFrame);
+
+type HandlerSlots = [Option<IrqHandler>; MAX_SHARED_HANDLERS];
+
+static IRQ_HANDLERS: spin::Mutex<[HandlerSlots; IRQ_VECTORS]> =
+    spin::Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_VECTORS]);
+
+// Returned by `register_irq`, gives the handler back to `unregister_irq`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    vector: usize,
+    slot: usize,
+}
+
+/// Calls `handler` whenever `vector` arrives, after the handlers registered
+/// for it before. Returns None if `vector` is not an IRQ vector or already
+/// has `MAX_SHARED_HANDLERS` handlers.
+pub fn register_irq(vector: usize, handler: IrqHandler) -> Option<IrqHandle> {
+    if !(FIRST_IRQ_VECTOR..256).contains(&vector) {
+        return None;
+    }
+    // the lock is also taken by `irq_dispatch`
+    without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slots = &mut handlers[vector - FIRST_IRQ_VECTOR];
+        let slot = slots.iter().position(|handler| handler.is_none())?;
+        slots[slot] = Some(handler);
+        Some(IrqHandle { vector, slot })
+    })
+}
+
+/// Stops calling a handler from `register_irq`
+pub fn unregister_irq(handle: IrqHandle) {
+    without_interrupts(|| {
+        IRQ_HANDLERS.lock()[handle.vector - FIRST_IRQ_VECTOR][handle.slot] = None;
+    });
+}
+
+// Called from `irq_common` with the registers of the interrupted code
+#[no_mangle]
+pub extern "sysv64" fn irq_dispatch(frame: &mut TrapFrame, vector: usize) {
+    // acknowledged up front as a handler may switch away and not come back
+    // for a while, the next interrupt still waits for interrupts to be enabled
+    if vector != SPURIOUS_VECTOR {
+        unsafe {
+            apic_end_of_interrupt(get_apic_base());
+        }
+    }
+
+    // copied out so no lock is held while the handlers run
+    let slots = IRQ_HANDLERS.lock()[vector - FIRST_IRQ_VECTOR];
+    let mut handled = false;
+    for handler in slots.iter().flatten() {
+        handler(frame);
+        handled = true;
+    }
+    if !handled && vector != SPURIOUS_VECTOR {
+        println!("Unhandled interrupt vector {}", vector);
+    }
+}
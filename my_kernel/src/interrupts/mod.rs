@@ -1,8 +1,10 @@
 pub mod interrupt_handlers;
+pub mod irq;
 
 use crate::interrupts::interrupt_handlers::*;
+use crate::tss::DOUBLE_FAULT_IST_INDEX;
 use alloc::boxed::Box;
-use core::arch::asm;
+use core::arch::{asm, global_asm};
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem;
@@ -32,6 +34,20 @@ pub fn enable_hardware_interrupts() {
     }
 }
 
+// Runs `f` with interrupts disabled, they are enabled again afterwards if
+// they were before
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq; pop {}; cli", out(reg) rflags);
+    }
+    let result = f();
+    if rflags & 0x200 != 0 {
+        enable_hardware_interrupts();
+    }
+    result
+}
+
 impl fmt::Debug for InterruptStackFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!(
@@ -45,6 +61,7 @@ impl fmt::Debug for InterruptStackFrame {
 }
 
 // #[derive(Debug)]
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct InterruptStackFrame {
     pub rip: u64,
@@ -54,6 +71,124 @@ pub struct InterruptStackFrame {
     pub ss: u64,
 }
 
+// Registers saved by the assembly interrupt stubs below, in the order they
+// sit on the stack. `cr3` is the page table that was active when the
+// interrupt arrived and is restored by `interrupt_return`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub cr3: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+impl InterruptStackFrame {
+    // privilege level of the interrupted code is in the low bits of the saved cs
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+impl TrapFrame {
+    pub fn from_user_mode(&self) -> bool {
+        self.stack_frame.from_user_mode()
+    }
+}
+
+extern "C" {
+    fn irq_stubs();
+    pub fn interrupt_return();
+}
+
+// Each stub is 16 bytes apart
+const IRQ_STUB_SIZE: usize = 16;
+
+// There is a stub for every vector from 32 on, it saves rax and passes the
+// vector on to `irq_common` in it. `irq_common` saves the other general
+// purpose registers, since a handler may switch to another process, then moves
+// onto the kernel page table before calling `irq_dispatch`.
+// `interrupt_return` is also the first thing a new process runs, see `Process::new`.
+global_asm!(
+    ".altmacro
+    .macro irq_stub vector
+    .align 16
+    push rax
+    mov eax, \\vector
+    jmp irq_common
+    .endm
+
+    .align 16
+    .global irq_stubs
+    irq_stubs:
+    .set vector, 32
+    .rept 256 - 32
+    irq_stub %vector
+    .set vector, vector + 1
+    .endr
+    .noaltmacro
+
+    irq_common:
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rsi, rax
+    mov rax, cr3
+    push rax
+    mov rax, kern_cr3[rip]
+    mov cr3, rax
+    mov rdi, rsp
+    mov rbx, rsp
+    and rsp, -16
+    call irq_dispatch
+    mov rsp, rbx
+
+    .global interrupt_return
+    interrupt_return:
+    pop rax
+    mov cr3, rax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq"
+);
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct IDTEntryOptions {
@@ -139,6 +274,52 @@ impl<F> IDTEntry<F> {
             phantom: PhantomData,
         }
     }
+
+    fn set_addr(&mut self, addr: usize) -> &mut IDTEntryOptions {
+        self.addr_low = addr as u16;
+        self.addr_mid = (addr >> 16) as u16;
+        self.addr_high = (addr >> 32) as u32;
+
+        self.gdt_selector = 0x08;
+        self.options.set_present(true);
+        &mut self.options
+    }
+}
+
+// Handler types an entry can point at, `F` of an entry picks the one that
+// matches what the cpu pushes for that vector
+pub trait HandlerAddr {
+    fn addr(self) -> usize;
+}
+
+impl HandlerAddr for HandlerFunc {
+    fn addr(self) -> usize {
+        self as usize
+    }
+}
+
+impl HandlerAddr for DivergingHandlerFunc {
+    fn addr(self) -> usize {
+        self as usize
+    }
+}
+
+impl HandlerAddr for HandlerFuncWithErrCode {
+    fn addr(self) -> usize {
+        self as usize
+    }
+}
+
+impl HandlerAddr for DivergingHandlerFuncWithErrCode {
+    fn addr(self) -> usize {
+        self as usize
+    }
+}
+
+impl<F: HandlerAddr> IDTEntry<F> {
+    pub fn set_handler_fn(&mut self, handler: F) -> &mut IDTEntryOptions {
+        self.set_addr(handler.addr())
+    }
 }
 
 #[repr(align(8))]
@@ -159,13 +340,18 @@ struct InterruptDescriptorTable {
     stack_fault: IDTEntry<HandlerFuncWithErrCode>,
     general_protection: IDTEntry<HandlerFuncWithErrCode>,
     page_fault: IDTEntry<PageFaultHandlerFunc>,
+    reserved_15: IDTEntry<HandlerFunc>,
     x87_fpu_error: IDTEntry<HandlerFunc>,
-    reserved: IDTEntry<HandlerFunc>,
     alignment_check: IDTEntry<HandlerFuncWithErrCode>,
     machine_check: IDTEntry<DivergingHandlerFunc>,
     simd_fpu: IDTEntry<HandlerFunc>,
-    virtualization: IDTEntry<HandlerFunc>,     // 21
-    reserved_arr: [IDTEntry<HandlerFunc>; 11], // 32 or index 31
+    virtualization: IDTEntry<HandlerFunc>,
+    control_protection: IDTEntry<HandlerFuncWithErrCode>, // 21
+    reserved_22_27: [IDTEntry<HandlerFunc>; 6],
+    hypervisor_injection: IDTEntry<HandlerFunc>,
+    vmm_communication: IDTEntry<HandlerFuncWithErrCode>,
+    security: IDTEntry<HandlerFuncWithErrCode>,
+    reserved_31: IDTEntry<HandlerFunc>,
     extra: [IDTEntry<HandlerFunc>; 256 - 32],
 }
 
@@ -190,11 +376,6 @@ pub type HandlerFuncWithErrCode = extern "x86-interrupt" fn(_: InterruptStackFra
 pub type DivergingHandlerFuncWithErrCode =
     extern "x86-interrupt" fn(_: InterruptStackFrame, error: u64) -> !;
 
-#[repr(usize)]
-pub enum ExtraInterrupts {
-    ApicTimer = 32,
-}
-
 impl IDT {
     fn new() -> Self {
         IDT {
@@ -215,13 +396,18 @@ impl IDT {
                 stack_fault: IDTEntry::empty(),
                 general_protection: IDTEntry::empty(),
                 page_fault: IDTEntry::empty(),
-                reserved: IDTEntry::empty(),
+                reserved_15: IDTEntry::empty(),
                 x87_fpu_error: IDTEntry::empty(),
                 alignment_check: IDTEntry::empty(),
                 machine_check: IDTEntry::empty(),
                 simd_fpu: IDTEntry::empty(),
                 virtualization: IDTEntry::empty(),
-                reserved_arr: [IDTEntry::empty(); 11],
+                control_protection: IDTEntry::empty(),
+                reserved_22_27: [IDTEntry::empty(); 6],
+                hypervisor_injection: IDTEntry::empty(),
+                vmm_communication: IDTEntry::empty(),
+                security: IDTEntry::empty(),
+                reserved_31: IDTEntry::empty(),
                 extra: [IDTEntry::empty(); 256 - 32],
             },
         }
@@ -233,7 +419,7 @@ impl IDT {
         self.ptr.limit = (mem::size_of::<InterruptDescriptorTable>() - 1) as u16;
 
         let ptr = (&self.ptr) as *const _ as usize;
-        if ptr % 0x8 != 0 {
+        if !ptr.is_multiple_of(0x8) {
             panic!("IDT pointer not aligned");
         }
         unsafe {
@@ -242,88 +428,88 @@ impl IDT {
     }
 
     pub fn set_breakpoint_handler(&mut self, handler: HandlerFunc) -> &mut IDTEntryOptions {
-        let addr = handler as usize;
-
-        self.table.breakpoint.addr_low = addr as u16;
-        self.table.breakpoint.addr_mid = (addr >> 16) as u16;
-        self.table.breakpoint.addr_high = (addr >> 32) as u32;
-
-        self.table.breakpoint.gdt_selector = 0x08;
-        self.table.breakpoint.options.set_present(true);
-        &mut self.table.breakpoint.options
+        self.table.breakpoint.set_handler_fn(handler)
     }
 
     pub fn set_divide_error_handler(&mut self, handler: HandlerFunc) -> &mut IDTEntryOptions {
-        let addr = handler as usize;
-
-        self.table.divide_error.addr_low = addr as u16;
-        self.table.divide_error.addr_mid = (addr >> 16) as u16;
-        self.table.divide_error.addr_high = (addr >> 32) as u32;
-
-        self.table.divide_error.gdt_selector = 0x08;
-        self.table.divide_error.options.set_present(true);
-        &mut self.table.divide_error.options
+        self.table.divide_error.set_handler_fn(handler)
     }
 
     pub fn set_general_protection_handler(
         &mut self,
         handler: HandlerFuncWithErrCode,
     ) -> &mut IDTEntryOptions {
-        let addr = handler as usize;
-
-        self.table.general_protection.addr_low = addr as u16;
-        self.table.general_protection.addr_mid = (addr >> 16) as u16;
-        self.table.general_protection.addr_high = (addr >> 32) as u32;
-
-        self.table.general_protection.gdt_selector = 0x08;
-        self.table.general_protection.options.set_present(true);
-        self.table.general_protection.options.set_dpl(3);
-        &mut self.table.general_protection.options
+        self.table
+            .general_protection
+            .set_handler_fn(handler)
+            .set_dpl(3)
     }
 
     pub fn set_page_fault_handler(
         &mut self,
         handler: PageFaultHandlerFunc,
     ) -> &mut IDTEntryOptions {
-        let addr = handler as usize;
-
-        self.table.page_fault.addr_low = addr as u16;
-        self.table.page_fault.addr_mid = (addr >> 16) as u16;
-        self.table.page_fault.addr_high = (addr >> 32) as u32;
-
-        self.table.page_fault.gdt_selector = 0x08;
-        self.table.page_fault.options.set_present(true);
-        self.table.page_fault.options.set_dpl(3);
-        &mut self.table.page_fault.options
+        self.table.page_fault.set_handler_fn(handler).set_dpl(3)
     }
 
-    pub fn set_extra_handler(
-        &mut self,
-        handler: HandlerFunc,
-        index: ExtraInterrupts,
-    ) -> &mut IDTEntryOptions {
-        let addr = handler as usize;
-        let index = index as usize - 32;
-
-        self.table.extra[index].addr_low = addr as u16;
-        self.table.extra[index].addr_mid = (addr >> 16) as u16;
-        self.table.extra[index].addr_high = (addr >> 32) as u32;
-
-        self.table.extra[index].gdt_selector = 0x08;
-        self.table.extra[index].options.set_present(true);
-        &mut self.table.extra[index].options
+    // Points vectors 32-255 at their stubs, which hand them to the handlers
+    // from `register_irq`
+    pub fn set_irq_stubs(&mut self) {
+        for (index, entry) in self.table.extra.iter_mut().enumerate() {
+            entry.set_addr(irq_stubs as *const () as usize + index * IRQ_STUB_SIZE);
+        }
     }
 
     pub fn create_idt_on_heap() -> Box<IDT> {
         Box::new(IDT::new())
     }
 
+    // Every architectural exception gets a handler so none of them can hit an
+    // empty gate, which would triple fault
+    pub fn set_exception_handlers(&mut self) {
+        self.set_divide_error_handler(de_handler);
+        self.set_breakpoint_handler(bp_handler);
+        self.set_general_protection_handler(gp_handler);
+        self.set_page_fault_handler(pf_handler);
+
+        let table = &mut self.table;
+        table.debug_excepton.set_handler_fn(db_handler);
+        table.nmi.set_handler_fn(nmi_handler);
+        table.overflow.set_handler_fn(of_handler);
+        table.bound_range.set_handler_fn(br_handler);
+        table.invalid_opcode.set_handler_fn(ud_handler);
+        table.device_not_available.set_handler_fn(nm_handler);
+        // runs on its own stack, the kernel stack may be what overflowed
+        table
+            .double_fault
+            .set_handler_fn(df_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        table
+            .coprocessor_segment_overrun
+            .set_handler_fn(cso_handler);
+        table.invalid_tss.set_handler_fn(ts_handler);
+        table.segment_not_present.set_handler_fn(np_handler);
+        table.stack_fault.set_handler_fn(ss_handler);
+        table.x87_fpu_error.set_handler_fn(mf_handler);
+        table.alignment_check.set_handler_fn(ac_handler);
+        table.machine_check.set_handler_fn(mc_handler);
+        table.simd_fpu.set_handler_fn(xm_handler);
+        table.virtualization.set_handler_fn(ve_handler);
+        table.control_protection.set_handler_fn(cp_handler);
+        table.hypervisor_injection.set_handler_fn(hv_handler);
+        table.vmm_communication.set_handler_fn(vc_handler);
+        table.security.set_handler_fn(sx_handler);
+
+        table.reserved_15.set_handler_fn(reserved_handler);
+        for entry in table.reserved_22_27.iter_mut() {
+            entry.set_handler_fn(reserved_handler);
+        }
+        table.reserved_31.set_handler_fn(reserved_handler);
+    }
+
     pub fn setup_idt(idt: &mut Box<IDT>) {
-        idt.set_breakpoint_handler(bp_handler);
-        idt.set_divide_error_handler(de_handler);
-        idt.set_general_protection_handler(gp_handler);
-        idt.set_page_fault_handler(pf_handler);
-        idt.set_extra_handler(apic_timer_handler, ExtraInterrupts::ApicTimer);
+        idt.set_exception_handlers();
+        idt.set_irq_stubs();
         idt.load();
     }
 }
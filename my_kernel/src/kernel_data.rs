@@ -1,23 +1,52 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+use crate::elf::ProgHeaderEntry;
+use crate::fs::vfs::FileSystem;
+use crate::memory::frame_allocator::FrameAllocator;
+use crate::memory::page_table::{PhysPage4KiB, PML4};
+use crate::process::Process;
 use crate::tss::TSS;
 
-const MAX_PROCESSES: usize = 0x100;
+pub const MAX_PROCESSES: usize = 0x100;
 
-#[derive(Clone, Copy)]
-struct ProcessInfo {
-    _cr3: usize,
+pub struct KernelData {
+    pub tss: Option<Box<TSS>>,
+    // indexed by pid
+    pub processes: [Option<Process>; MAX_PROCESSES],
+    // pids of processes waiting for the cpu, front runs next
+    pub run_queue: VecDeque<usize>,
+    pub current: Option<usize>,
+    // stack pointer of the idle loop in `scheduler::start` while a process runs
+    pub idle_rsp: usize,
+    // root filesystem, everything opened by path goes here
+    pub fs: Option<Box<dyn FileSystem>>,
 }
 
-pub struct KernelData {
-    _tss: Option<TSS>,
-    _process_info: [Option<ProcessInfo>; MAX_PROCESSES],
+// Kept apart from `KernelData` so memory can be managed while the process
+// table is held. When both are needed lock `KERNEL_DATA` first.
+pub struct KernelMemory {
+    pub frame_alloc: FrameAllocator,
+    pub pml4: &'static mut PML4,
+    // loadable segments of the kernel, these get mapped into every process
+    pub prog_header_entries: Vec<ProgHeaderEntry>,
+    pub stack_phys: &'static PhysPage4KiB,
 }
 
 lazy_static! {
-    pub static ref KERNEL_DATA: Mutex<KernelData> = Mutex::new(KernelData {
-        _tss: None,
-        _process_info: [None; MAX_PROCESSES],
-    });
+    pub static ref KERNEL_DATA: Mutex<KernelData> = {
+        const EMPTY: Option<Process> = None;
+        Mutex::new(KernelData {
+            tss: None,
+            processes: [EMPTY; MAX_PROCESSES],
+            run_queue: VecDeque::new(),
+            current: None,
+            idle_rsp: 0,
+            fs: None,
+        })
+    };
+    pub static ref KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
 }
@@ -11,6 +11,7 @@
 pub use bootloader_structs::BootInfo;
 use init::phase1::phase1_init;
 
+pub mod acpi;
 pub mod ahci;
 pub mod apic;
 pub mod bootloader_structs;
@@ -24,6 +25,7 @@ pub mod interrupts;
 pub mod kernel_data;
 pub mod memory;
 pub mod pci;
+pub mod process;
 pub mod tss;
 pub mod user_mode;
 pub mod vga_buffer;
@@ -2,154 +2,296 @@ use crate::bootloader_structs::{BootInfo, E820MemoryRegion};
 use crate::elf::ProgHeaderEntry;
 use crate::memory::page_table::*;
 use crate::println;
-use alloc::vec::Vec;
 use core::convert::TryInto;
 use core::mem;
 use core::slice;
 
 const INITIAL_STACK_SIZE: usize = 0x2000;
 
+// The frame table is mapped here once the identity map is gone
+pub const FRAME_TABLE_START: usize = 0xFFFF_9000_0000_0000;
+
+pub const FRAMES_PER_2MIB: usize = 0x200;
+
+// set while a frame is shared copy on write between address spaces
+pub const FRAME_COPY_ON_WRITE: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameOwner {
+    // can be allocated
+    Free,
+    // not usable, or in use before the allocator existed, never handed out
+    Reserved,
+    Kernel,
+    User,
+}
+
+// What is known about one physical frame, the frame table has one of these
+// for every frame up to the end of usable memory
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FrameInfo {
+    // mappings of the frame, 0 while it is free
+    pub refcount: u16,
+    pub owner: FrameOwner,
+    pub flags: u8,
+}
+
+// Hands out frames using a bitmap of the free ones kept after the frame table.
+// Searches go a word of 64 frames at a time, so physically contiguous runs
+// can be found without walking every entry.
 #[derive(Debug)]
-pub struct LinkedListFrameAllocator {
+pub struct FrameAllocator {
+    // free frames left
     pub frame_count: u64,
-    pub next: usize,
+    // frame table index the next single frame search starts at
+    next_index: usize,
+    // where the frame table can be accessed, physical until phase 2
+    frame_table: usize,
+    frame_table_phys: usize,
+    // entries in the frame table
+    total_frames: usize,
 }
 
-impl LinkedListFrameAllocator {
+impl FrameAllocator {
     pub fn init(boot_info: &BootInfo) -> Self {
-        let (frame_count, first_page) = init_frames(boot_info);
-        LinkedListFrameAllocator {
+        let (frame_count, frame_table_phys, total_frames) = init_frames(boot_info);
+        FrameAllocator {
             frame_count,
-            next: first_page,
+            next_index: 0,
+            frame_table: frame_table_phys,
+            frame_table_phys,
+            total_frames,
         }
     }
 
-    // this only works when identity mapped because the next points to some
-    // physical addr
-    pub fn allocate(&mut self) -> Option<&'static PhysPage4KiB> {
-        if self.frame_count != 0 {
-            self.frame_count -= 1;
-            unsafe {
-                let ptr = self.next as *mut usize;
-                self.next = *ptr;
-                return Some(&*(ptr as *const PhysPage4KiB));
-            }
+    // Switches to the `FRAME_TABLE_START` mapping, after this the table is
+    // only reachable through page tables that have it mapped
+    pub fn relocate_frame_table(&mut self) {
+        self.frame_table = FRAME_TABLE_START;
+    }
+
+    // Physical address and size in pages of the frame table and its bitmap
+    pub fn frame_table_region(&self) -> (usize, usize) {
+        (
+            self.frame_table_phys,
+            table_bytes(self.total_frames).div_ceil(0x1000),
+        )
+    }
+
+    fn frame_table(&self) -> &'static mut [FrameInfo] {
+        unsafe { slice::from_raw_parts_mut(self.frame_table as *mut FrameInfo, self.total_frames) }
+    }
+
+    // one bit per frame, set while it is free
+    fn bitmap(&self) -> &'static mut [u64] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                (self.frame_table + bitmap_offset(self.total_frames)) as *mut u64,
+                self.total_frames.div_ceil(64),
+            )
         }
-        if self.next != 0xdeadbeef {
-            panic!("Next was not set to the end singifier");
+    }
+
+    fn set_free_bit(&mut self, index: usize, free: bool) {
+        let word = &mut self.bitmap()[index / 64];
+        if free {
+            *word |= 1 << (index % 64);
+        } else {
+            *word &= !(1 << (index % 64));
         }
-        None
     }
 
-    pub fn deallocate(&mut self, page: &mut PhysPage4KiB) {
-        if self.frame_count == 0 {
-            self.frame_count = 1;
-            unsafe {
-                let ptr = page as *const PhysPage4KiB as usize as *mut usize;
-                *ptr = 0xdeadbeef;
-                self.next = ptr as usize;
+    // Highest frame in `[start, end)` that is not free
+    fn last_used(&self, start: usize, end: usize) -> Option<usize> {
+        let bitmap = self.bitmap();
+        let mut word = (end - 1) / 64;
+        loop {
+            let word_start = word * 64;
+            let mut used = !bitmap[word];
+            if end < word_start + 64 {
+                used &= (1 << (end - word_start)) - 1;
             }
-        } else {
-            self.frame_count += 1;
-            unsafe {
-                let ptr = page as *const PhysPage4KiB as usize as *mut usize;
-                let ptr_next = self.next as *const PhysPage4KiB as usize;
-                *ptr = ptr_next;
-                self.next = ptr as usize;
+            if start > word_start {
+                used &= !((1 << (start - word_start)) - 1);
+            }
+            if used != 0 {
+                return Some(word_start + 63 - used.leading_zeros() as usize);
             }
+            if word_start <= start {
+                return None;
+            }
+            word -= 1;
         }
     }
 
-    // This needs to be used when memory is not identity mapped and fully mapped
-    // This will need to map in the frame in order to get the 'next' pointer
-    pub fn allocate_and_map(
-        &mut self,
-        pml4: &mut PML4,
-        vaddr: usize,
-        heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-    ) -> Option<(&'static VirtPage4KiB, usize)> {
-        if self.frame_count != 0 {
-            self.frame_count -= 1;
-            unsafe {
-                // phys addr
-                let phys_page = self.next;
-                let virt_page = vaddr;
-                // must map phys addr to some virt addr
-                // then get the new next pointer from virt addr
-                pml4.map_frame_4k(phys_page, virt_page, true, true, Some(heap_regions));
-
-                let next = *(virt_page as *mut usize);
-
-                self.next = next;
-                return Some((&*(virt_page as *const VirtPage4KiB), phys_page));
-            }
+    pub fn info(&self, paddr: usize) -> FrameInfo {
+        self.frame_table()[paddr / 0x1000]
+    }
+
+    fn info_mut(&mut self, paddr: usize) -> &'static mut FrameInfo {
+        match self.frame_table().get_mut(paddr / 0x1000) {
+            Some(info) => info,
+            None => panic!("Frame {:#x} is past the end of the frame table", paddr),
+        }
+    }
+
+    fn mark_allocated(&mut self, paddr: usize) {
+        let info = self.info_mut(paddr);
+        if info.owner != FrameOwner::Free {
+            panic!("Allocated frame {:#x} that was not free: {:?}", paddr, info);
+        }
+        *info = FrameInfo {
+            refcount: 1,
+            owner: FrameOwner::Kernel,
+            flags: 0,
+        };
+        self.set_free_bit(paddr / 0x1000, false);
+        self.frame_count -= 1;
+    }
+
+    fn mark_free(&mut self, paddr: usize) {
+        let info = self.info_mut(paddr);
+        if info.owner == FrameOwner::Free || info.owner == FrameOwner::Reserved {
+            panic!(
+                "Freed frame {:#x} that was not allocated: {:?}",
+                paddr, info
+            );
+        }
+        *info = FrameInfo {
+            refcount: 0,
+            owner: FrameOwner::Free,
+            flags: 0,
+        };
+        self.set_free_bit(paddr / 0x1000, true);
+        self.frame_count += 1;
+    }
+
+    // End of the highest frame in the frame table
+    pub fn phys_limit(&self) -> usize {
+        self.total_frames * 0x1000
+    }
+
+    // Frames start out owned by the kernel
+    pub fn set_owner(&mut self, paddr: usize, owner: FrameOwner) {
+        self.info_mut(paddr).owner = owner;
+    }
+
+    pub fn refcount(&self, paddr: usize) -> u16 {
+        self.info(paddr).refcount
+    }
+
+    // Another address space maps `paddr` copy on write
+    pub fn share(&mut self, paddr: usize) {
+        let info = self.info_mut(paddr);
+        info.refcount = info
+            .refcount
+            .checked_add(1)
+            .expect("Frame refcount overflow");
+        info.flags |= FRAME_COPY_ON_WRITE;
+    }
+
+    // One mapping of `paddr` is gone, returns true if it was the last one and
+    // the frame should be deallocated
+    pub fn release(&mut self, paddr: usize) -> bool {
+        let info = self.info_mut(paddr);
+        if info.refcount == 0 {
+            panic!("Released frame {:#x} with no references: {:?}", paddr, info);
+        }
+        info.refcount -= 1;
+        if info.refcount <= 1 {
+            info.flags &= !FRAME_COPY_ON_WRITE;
+        }
+        info.refcount == 0
+    }
+
+    // How many frames `owner` holds, for finding leaks
+    pub fn count_owned(&self, owner: FrameOwner) -> usize {
+        self.frame_table()
+            .iter()
+            .filter(|info| info.owner == owner)
+            .count()
+    }
+
+    pub fn allocate(&mut self) -> Option<&'static PhysPage4KiB> {
+        let paddr = self.allocate_frame()?;
+        unsafe { Some(&*(paddr as *const PhysPage4KiB)) }
+    }
+
+    // Returns the physical address of a free frame
+    pub fn allocate_frame(&mut self) -> Option<usize> {
+        if self.frame_count == 0 {
+            return None;
         }
-        if self.next != 0xdeadbeef {
-            panic!("Next was not set to the end singifier");
+        let bitmap = self.bitmap();
+        let first_word = self.next_index / 64;
+        let word = (first_word..bitmap.len())
+            .chain(0..first_word)
+            .find(|&word| bitmap[word] != 0)?;
+        let index = word * 64 + bitmap[word].trailing_zeros() as usize;
+        self.mark_allocated(index * 0x1000);
+        self.next_index = index + 1;
+        Some(index * 0x1000)
+    }
+
+    // Finds `count` free frames in a row, the first one on a multiple of
+    // `align` frames. Returns the physical address of the first.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || align == 0 || (self.frame_count as usize) < count {
+            return None;
+        }
+        let mut start = 0;
+        while start + count <= self.total_frames {
+            match self.last_used(start, start + count) {
+                // no run starting before the used frame can fit
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for index in start..start + count {
+                        self.mark_allocated(index * 0x1000);
+                    }
+                    return Some(start * 0x1000);
+                }
+            }
         }
         None
     }
 
-    pub fn allocate_and_map_for_other(
+    // A 2MiB aligned frame for a big page
+    pub fn allocate_2mib(&mut self) -> Option<&'static PhysPage2MiB> {
+        let paddr = self.allocate_contiguous(FRAMES_PER_2MIB, FRAMES_PER_2MIB)?;
+        unsafe { Some(&*(paddr as *const PhysPage2MiB)) }
+    }
+
+    pub fn deallocate(&mut self, paddr: usize) {
+        self.mark_free(paddr);
+    }
+
+    // Frees a run from `allocate_contiguous`
+    pub fn deallocate_contiguous(&mut self, paddr: usize, count: usize) {
+        for frame in 0..count {
+            self.mark_free(paddr + frame * 0x1000);
+        }
+    }
+
+    // Allocates a frame and maps it at `vaddr` in `pml4`
+    pub fn allocate_and_map(
         &mut self,
         pml4: &mut PML4,
-        other_pml4: &mut PML4,
         vaddr: usize,
-        heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-    ) -> Option<&'static VirtPage4KiB> {
-        if self.frame_count != 0 {
-            self.frame_count -= 1;
-            unsafe {
-                // phys addr
-                let phys_page = self.next;
-                let virt_page = vaddr;
-                // must map phys addr to some virt addr
-                // then get the new next pointer from virt addr
-                pml4.map_frame_4k(phys_page, virt_page, true, true, Some(heap_regions));
-                other_pml4.map_frame_4k(phys_page, virt_page, true, true, Some(heap_regions));
-
-                let next = *(virt_page as *mut usize);
-
-                pml4.unmap_frame_4k(&*(virt_page as *const VirtPage4KiB), Some(heap_regions));
-
-                self.next = next;
-                return Some(&*(virt_page as *const VirtPage4KiB));
-            }
-        }
-        if self.next != 0xdeadbeef {
-            panic!("Next was not set to the end singifier");
+        flags: PageFlags,
+    ) -> Option<(&'static VirtPage4KiB, usize)> {
+        let phys_page = self.allocate_frame()?;
+        unsafe {
+            pml4.map_frame_4k(phys_page, vaddr, flags);
+            Some((&*(vaddr as *const VirtPage4KiB), phys_page))
         }
-        None
     }
-
-    //     pub fn deallocate_and_unmap(
-    //         &mut self,
-    //         pml4: &mut PML4,
-    //         page: &VirtPage4KiB,
-    //         heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    //     ) {
-    //         if self.frame_count == 0 {
-    //             self.frame_count = 1;
-    //             unsafe {
-    //                 let ptr = page as *const VirtPage4KiB as usize as *mut usize;
-    //                 *ptr = 0xdeadbeef;
-    //                 let phys_page = pml4.unmap_frame_4k(page, Some(heap_regions));
-    //                 self.next = phys_page as *const PhysPage4KiB as usize;
-    //             }
-    //         } else {
-    //             self.frame_count += 1;
-    //             unsafe {
-    //                 let ptr = page as *const VirtPage4KiB as usize as *mut usize;
-    //                 let ptr_next = self.next as *const VirtPage4KiB as usize;
-    //                 *ptr = ptr_next;
-    //                 let phys_page = pml4.unmap_frame_4k(page, Some(heap_regions));
-    //                 self.next = phys_page as *const PhysPage4KiB as usize;
-    //             }
-    //         }
-    //     }
 }
 
-fn init_frames(boot_info: &BootInfo) -> (u64, usize) {
+// Returns the number of free frames and the physical address and length of
+// the frame table
+fn init_frames(boot_info: &BootInfo) -> (u64, usize, usize) {
     println!("Initializing physical memory for frame allocator");
     let mm = {
         let ptr = boot_info.mem_map as *const E820MemoryRegion;
@@ -169,7 +311,7 @@ fn init_frames(boot_info: &BootInfo) -> (u64, usize) {
     get_elf_regions(boot_info, mm)
 }
 
-fn get_elf_regions(boot_info: &BootInfo, mem_map: &[E820MemoryRegion]) -> (u64, usize) {
+fn get_elf_regions(boot_info: &BootInfo, mem_map: &[E820MemoryRegion]) -> (u64, usize, usize) {
     let e = {
         let ptr = boot_info.elf_location as *const u8;
         unsafe { slice::from_raw_parts(ptr, boot_info.elf_size as usize) }
@@ -234,15 +376,44 @@ fn get_elf_regions(boot_info: &BootInfo, mem_map: &[E820MemoryRegion]) -> (u64,
     // get valid page ranges
     let mut usable_pages = 0;
     let mut unusable_pages = 0;
-    let mut first_page: usize = 0;
-    let mut last_good_page: u64 = 0;
-    let mut first_page_set = false;
 
     let (pt_min, pt_max) = get_page_table_min_max();
 
     let stack_end_page = (boot_info.stack_location - 1) & 0xfffffffffffff000; // align to 0x1000
     let stack_start_page = stack_end_page - INITIAL_STACK_SIZE;
 
+    // check that nothing important is in this page
+    // check stack, loaded elf segments, etc
+    // since these are all page aligned this makes it easier
+    let in_use = |page_addr: u64| {
+        // segments
+        if page_addr >= seg_lowest as u64 && page_addr <= seg_greatest as u64 {
+            for (start_page, end_page) in seg_avoid {
+                if start_page == 0 {
+                    break;
+                }
+                if page_addr >= start_page as u64 && page_addr <= end_page as u64 {
+                    return true;
+                }
+            }
+            false
+        } else {
+            (page_addr >= stack_end_page as u64 && page_addr <= stack_start_page as u64)
+                || (page_addr >= pt_min
+                    && page_addr <= pt_max
+                    && check_page_table_overlap(page_addr))
+        }
+    };
+
+    let (table_start, total_frames) = place_frame_table(mem_map, &in_use);
+    let table_end = table_start + table_bytes(total_frames);
+    let table = unsafe { slice::from_raw_parts_mut(table_start as *mut FrameInfo, total_frames) };
+    table.fill(FrameInfo {
+        refcount: 0,
+        owner: FrameOwner::Reserved,
+        flags: 0,
+    });
+
     for region in mem_map.iter() {
         if region.start_addr == 0x0 || region.region_type != 1 {
             continue;
@@ -250,55 +421,29 @@ fn get_elf_regions(boot_info: &BootInfo, mem_map: &[E820MemoryRegion]) -> (u64,
         let page_count = region.len / 0x1000;
         for i in 0..page_count {
             let page_addr = region.start_addr + (i * 0x1000);
-            // check that nothing important is in this page
-            // check stack, loaded elf segments, etc
-            // since these are all page aligned this makes it easier
-
-            // segments
-            if page_addr >= seg_lowest as u64 && page_addr <= seg_greatest as u64 {
-                let mut skip_page = false;
-                for (start_page, end_page) in seg_avoid {
-                    if start_page == 0 {
-                        break;
-                    }
-                    if page_addr >= start_page as u64 && page_addr <= end_page as u64 {
-                        skip_page = true;
-                        break;
-                    }
-                }
-                if skip_page {
-                    unusable_pages += 1;
-                    continue;
-                }
-            } else if (page_addr >= stack_end_page as u64 && page_addr <= stack_start_page as u64)
-                || (page_addr >= pt_min
-                    && page_addr <= pt_max
-                    && check_page_table_overlap(page_addr))
+            if in_use(page_addr)
+                || (page_addr as usize + 0x1000 > table_start && (page_addr as usize) < table_end)
             {
                 unusable_pages += 1;
                 continue;
             }
 
             usable_pages += 1;
-
-            if !first_page_set {
-                first_page_set = true;
-                first_page = page_addr as usize;
-            }
-
-            // point every frame to the next one so its a loop
-            if last_good_page != 0 {
-                unsafe {
-                    let ptr = last_good_page as *mut usize;
-                    *ptr = page_addr as usize;
-                }
-            }
-            last_good_page = page_addr;
+            table[page_addr as usize / 0x1000].owner = FrameOwner::Free;
         }
     }
-    unsafe {
-        let ptr = last_good_page as *mut usize;
-        *ptr = 0xdeadbeef;
+
+    let bitmap = unsafe {
+        slice::from_raw_parts_mut(
+            (table_start + bitmap_offset(total_frames)) as *mut u64,
+            total_frames.div_ceil(64),
+        )
+    };
+    bitmap.fill(0);
+    for (index, info) in table.iter().enumerate() {
+        if info.owner == FrameOwner::Free {
+            bitmap[index / 64] |= 1 << (index % 64);
+        }
     }
 
     println!("Phys Mem Initialized");
@@ -306,7 +451,50 @@ fn get_elf_regions(boot_info: &BootInfo, mem_map: &[E820MemoryRegion]) -> (u64,
         "Usable pages: {:#x} Unusable pages: {:#x}",
         usable_pages, unusable_pages
     );
-    (usable_pages, first_page)
+    (usable_pages, table_start, total_frames)
+}
+
+// The free bitmap starts here, after the frame table
+fn bitmap_offset(total_frames: usize) -> usize {
+    (total_frames * mem::size_of::<FrameInfo>()).next_multiple_of(8)
+}
+
+// Size of the frame table and the bitmap after it
+fn table_bytes(total_frames: usize) -> usize {
+    bitmap_offset(total_frames) + total_frames.div_ceil(64) * 8
+}
+
+// Finds room for a frame table covering every usable frame at the end of the
+// highest usable region it fits in. Returns its address and number of entries.
+fn place_frame_table(mem_map: &[E820MemoryRegion], in_use: &dyn Fn(u64) -> bool) -> (usize, usize) {
+    let mem_end = mem_map
+        .iter()
+        .filter(|region| region.region_type == 1)
+        .map(|region| region.start_addr + region.len)
+        .max()
+        .expect("No usable memory");
+    let total_frames = (mem_end / 0x1000) as usize;
+    let table_size = (table_bytes(total_frames) as u64 + 0xfff) & 0xfffffffffffff000;
+
+    for region in mem_map.iter().rev() {
+        if region.start_addr == 0x0 || region.region_type != 1 {
+            continue;
+        }
+        let region_end = (region.start_addr + region.len) & 0xfffffffffffff000;
+        let start = match region_end.checked_sub(table_size) {
+            Some(start) if start >= region.start_addr => start,
+            _ => continue,
+        };
+        if (start..region_end).step_by(0x1000).any(in_use) {
+            continue;
+        }
+        println!(
+            "Frame table at {:#x} with {:#x} entries",
+            start, total_frames
+        );
+        return (start as usize, total_frames);
+    }
+    panic!("No room for the frame table");
 }
 
 fn get_page_table_min_max() -> (u64, u64) {
@@ -1,9 +1,11 @@
-use crate::memory::frame_allocator::LinkedListFrameAllocator;
+use crate::kernel_data::KernelMemory;
+use crate::memory::frame_allocator::{FrameAllocator, FRAMES_PER_2MIB};
 use crate::memory::heap::block_alloc::BlockAllocator;
-use crate::memory::page_table::PhysPage4KiB;
+use crate::memory::page_table::{PageFlags, PhysPage4KiB, PML4};
 use crate::println;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub mod block_alloc;
 pub mod linked_list_alloc;
@@ -11,201 +13,202 @@ pub mod linked_list_alloc;
 pub const HEAP_START: usize = 0xFFFF_A000_0000_0000;
 pub const HEAP_SIZE: usize = 8192 * 1024; // 8192 KiB, this should always be a multiple of 4KiB
 
+// virtual space kept free after `HEAP_START` for the heap to grow into
+pub const HEAP_MAX_SIZE: usize = 0x1000_0000; // 256 MiB
+
+// smallest amount the heap grows by, whole page tables at a time
+const HEAP_GROW_SIZE: usize = 0x20_0000;
+// grown memory comes in one physical run when there is one, else a frame at a
+// time. Frames that follow each other in physical memory share a region. The
+// regions are a fixed array so growing never allocates, once they are used up
+// the heap can only grow by extending the last one.
+const MAX_HEAP_REGIONS: usize = 512;
+
 #[global_allocator]
 static ALLOCATOR: Locked<BlockAllocator> = Locked::new(BlockAllocator::new());
 
-// Called while identity mapped
-pub fn init_heap_phase1(
-    frame_alloc: &mut LinkedListFrameAllocator,
-) -> (&'static PhysPage4KiB, usize) {
-    if HEAP_START % 0x1000 != 0 || HEAP_SIZE % 0x1000 != 0 {
-        panic!("HEAP not 4KiB aligned");
-    }
+// Physical runs backing the heap as (start, size), in the order they follow
+// each other from `HEAP_START`
+struct HeapRegions {
+    regions: [(usize, usize); MAX_HEAP_REGIONS],
+    len: usize,
+}
 
-    let mut last_page: usize = 0x0;
-    let mut first_page: usize = 0x0;
-    let mut pages: usize = 0;
-    for _page in (HEAP_START..HEAP_START + HEAP_SIZE).step_by(0x1000) {
-        if let Some(new_page) = frame_alloc.allocate() {
-            let new_page = new_page as *const PhysPage4KiB as usize;
-            if last_page != 0 && last_page + 0x1000 != new_page {
-                unsafe {
-                    frame_alloc.deallocate(&mut *(new_page as *mut PhysPage4KiB));
-                    ALLOCATOR.lock().init(first_page, pages * 0x1000);
-                    println!(
-                        "Partially initialized heap with size {} KiB",
-                        (pages * 0x1000) / 1024
-                    );
-                    return (&*(first_page as *const PhysPage4KiB), pages);
-                }
-            }
-            pages += 1;
-            if first_page == 0 {
-                first_page = new_page;
-            }
-            last_page = new_page;
-        } else {
-            panic!("Out of Pages");
+static HEAP_REGIONS: spin::Mutex<HeapRegions> = spin::Mutex::new(HeapRegions {
+    regions: [(0, 0); MAX_HEAP_REGIONS],
+    len: 0,
+});
+
+// Appends `size` bytes at `phys_start` to the heap, returns false if that
+// needs a new region and there is no room for one
+fn add_heap_region(phys_start: usize, size: usize) -> bool {
+    let mut heap_regions = HEAP_REGIONS.lock();
+    let len = heap_regions.len;
+    if len > 0 {
+        let (last_start, last_size) = &mut heap_regions.regions[len - 1];
+        if *last_start + *last_size == phys_start {
+            *last_size += size;
+            return true;
         }
     }
-    unsafe {
-        ALLOCATOR.lock().init(first_page, HEAP_SIZE);
-        println!(
-            "Fully initialized heap with size {} KiB",
-            (pages * 0x1000) / 1024
-        );
-        (&*(first_page as *const PhysPage4KiB), pages)
+    if len == MAX_HEAP_REGIONS {
+        return false;
     }
+    heap_regions.regions[len] = (phys_start, size);
+    heap_regions.len += 1;
+    true
 }
 
-pub fn init_heap_phase2(
-    frame_alloc: &mut LinkedListFrameAllocator,
-    pages_used: usize,
-) -> Vec<(&'static PhysPage4KiB, usize)> {
-    let increase_size = HEAP_SIZE - (pages_used * 0x1000);
-    let new_start = HEAP_START + (pages_used * 0x1000);
-    let mut phys_regions = Vec::new();
-    if increase_size == 0 {
-        return phys_regions;
+// Heap address of `paddr`, for memory found by its physical address like page
+// tables. Only valid once the heap is mapped at `HEAP_START`. This scans the
+// regions in order, which stays cheap as long as growing finds physical runs.
+pub fn phys_to_heap(paddr: usize) -> usize {
+    let heap_regions = HEAP_REGIONS.lock();
+    let mut vaddr = HEAP_START;
+    for &(start, size) in &heap_regions.regions[..heap_regions.len] {
+        if (start..start + size).contains(&paddr) {
+            return vaddr + (paddr - start);
+        }
+        vaddr += size;
     }
-    if HEAP_START % 0x1000 != 0 || HEAP_SIZE % 0x1000 != 0 {
+    panic!("{:#x} is not on the heap", paddr);
+}
+
+// Called while identity mapped. The initial heap is one physically contiguous
+// run, memory it grows by later need not be, so DMA buffers must not assume it
+// is and get their own frames instead.
+pub fn init_heap(frame_alloc: &mut FrameAllocator) -> Vec<(&'static PhysPage4KiB, usize)> {
+    if !HEAP_START.is_multiple_of(0x1000) || !HEAP_SIZE.is_multiple_of(0x1000) {
         panic!("HEAP not 4KiB aligned");
     }
 
-    let mut last_page: usize = 0x0;
-    let mut first_page: usize = 0x0;
-    let mut pages: usize = 0;
-    for _page in (new_start..HEAP_START + HEAP_SIZE).step_by(0x1000) {
-        if let Some(new_page) = frame_alloc.allocate() {
-            let new_page = new_page as *const PhysPage4KiB as usize;
-            if last_page != 0 && last_page + 0x1000 != new_page {
-                unsafe {
-                    ALLOCATOR.lock().extend(first_page, increase_size);
-                    phys_regions.push((&*(first_page as *const PhysPage4KiB), pages));
-                    println!("Extended heap with size {} KiB", (pages * 0x1000) / 1024);
-                }
-                first_page = new_page;
-                pages = 0;
-            }
-            pages += 1;
-            if first_page == 0 {
-                first_page = new_page;
-            }
-            last_page = new_page;
-        } else {
-            panic!("Out of Pages");
-        }
-    }
+    let pages = HEAP_SIZE / 0x1000;
+    // aligned so `map_heap` can use huge pages
+    let first_page = frame_alloc
+        .allocate_contiguous(pages, FRAMES_PER_2MIB)
+        .expect("No contiguous region for the heap");
     unsafe {
-        ALLOCATOR.lock().extend(first_page, increase_size);
-        phys_regions.push((&*(first_page as *const PhysPage4KiB), pages));
-        println!("Extended heap with size {} KiB", (pages * 0x1000) / 1024);
-        phys_regions
+        ALLOCATOR.lock().init(first_page, HEAP_SIZE);
     }
+    assert!(add_heap_region(first_page, HEAP_SIZE));
+    println!("Initialized heap with size {} KiB", HEAP_SIZE / 1024);
+    let mut phys_regions = Vec::new();
+    phys_regions.push((unsafe { &*(first_page as *const PhysPage4KiB) }, pages));
+    phys_regions
 }
 
-/// # Safety
-/// This should only be called for when `object` is a reference to virtual mem and we want to translate it to physical
-pub unsafe fn translate_mut_ref_to_phys<'a, T>(
-    heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    object: &'a mut T,
-) -> &'a mut T {
-    let o = object as *const T as usize;
-    // println!("orig addr: {:#x}", o);
-    let mut offset: usize = (o - HEAP_START) & 0xffff_ffff_ffff_f000;
-    for (start_page, num_pages) in heap_regions {
-        let offset_in_pages = offset / 0x1000;
-        if offset_in_pages > *num_pages {
-            offset -= num_pages * 0x1000;
-            continue;
-        }
-        // should be in this region
-        let start_page = (*start_page) as *const PhysPage4KiB as usize;
-        let phys_page = start_page + offset;
-        let phys_addr = phys_page + (o & 0xfff);
-        // println!("new addr: {:#x}", phys_addr);
-        let phys_ref = &mut (*(phys_addr as *mut T));
-        return phys_ref;
+// Set while `HeapGrowth::grow` runs. The heap is locked then, so an allocation
+// would spin on the lock forever.
+static GROWING: AtomicBool = AtomicBool::new(false);
+
+// Called by the global allocator before it takes the heap lock
+pub fn assert_not_growing() {
+    if GROWING.load(Ordering::Relaxed) {
+        panic!("Heap used while growing the heap");
     }
-    panic!("Did not find region");
 }
 
-/// # Safety
-/// This should only be called for when `object` is a reference to virtual mem and we want to translate it to physical
-pub unsafe fn translate_ref_to_phys<'a, T>(
-    heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    object: &'a T,
-) -> &'a T {
-    let o = object as *const T as usize;
-    // println!("orig addr: {:#x}", o);
-    let mut offset: usize = (o - HEAP_START) & 0xffff_ffff_ffff_f000;
-    for (start_page, num_pages) in heap_regions {
-        let offset_in_pages = offset / 0x1000;
-        if offset_in_pages > *num_pages {
-            offset -= num_pages * 0x1000;
-            continue;
-        }
-        // should be in this region
-        let start_page = (*start_page) as *const PhysPage4KiB as usize;
-        let phys_page = start_page + offset;
-        let phys_addr = phys_page + (o & 0xfff);
-        // println!("new addr: {:#x}", phys_addr);
-        let phys_ref = &mut (*(phys_addr as *mut T));
-        return phys_ref;
-    }
-    panic!("Did not find region");
+// What the allocator needs to map more memory after the end of the heap. These
+// point into `KERNEL_MEMORY`, which is usually locked while allocating, so the
+// heap reaches past the lock. Growing must not touch the heap itself, so it
+// only uses:
+// - `FrameAllocator` allocating and freeing, which only works on the frame
+//   table
+// - `PML4::map_frame_4k`, `translate` and `clear_mapping` on the heap tables
+//   `map_heap` reserved, so no table is ever allocated, split or freed
+// `GROWING` turns any heap use from them into a panic instead of a deadlock.
+pub struct HeapGrowth {
+    frame_alloc: *mut FrameAllocator,
+    pml4: *mut PML4,
+    // first unmapped address after the heap
+    end: usize,
 }
 
-/// # Safety
-/// This should only be called for when `object` is a reference to virtual mem and we want to translate it to physical
-pub unsafe fn translate_usize_to_phys(
-    heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    object: usize,
-) -> usize {
-    let o = object;
-    // println!("orig addr: {:#x}", o);
-    let mut offset: usize = (o - HEAP_START) & 0xffff_ffff_ffff_f000;
-    for (start_page, num_pages) in heap_regions {
-        let offset_in_pages = offset / 0x1000;
-        if offset_in_pages > *num_pages {
-            offset -= num_pages * 0x1000;
-            continue;
+unsafe impl Send for HeapGrowth {}
+
+impl HeapGrowth {
+    // Maps at least `min_size` bytes after the heap end and returns where the
+    // new memory starts and how much there is
+    unsafe fn grow(&mut self, min_size: usize) -> Option<(usize, usize)> {
+        let size = min_size.next_multiple_of(HEAP_GROW_SIZE);
+        if self.end + size > HEAP_START + HEAP_MAX_SIZE {
+            return None;
         }
-        // should be in this region
-        let start_page = (*start_page) as *const PhysPage4KiB as usize;
-        let phys_page = start_page + offset;
-        let phys_addr = phys_page + (o & 0xfff);
-        // println!("new addr: {:#x}", phys_addr);
-        let phys_ref = phys_addr;
-        return phys_ref;
+        GROWING.store(true, Ordering::Relaxed);
+        let mapped = self.map_frames(size);
+        GROWING.store(false, Ordering::Relaxed);
+        if !mapped {
+            return None;
+        }
+
+        let start = self.end;
+        self.end += size;
+        Some((start, size))
     }
-    panic!("Did not find region");
-}
 
-/// # Safety
-/// This should only be called for when `object` is a reference to physical mem and we want to translate it to virtual
-pub unsafe fn translate_ref_to_virt<'a, T>(
-    heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-    object: &'a mut T,
-) -> &'a mut T {
-    let mut o = object as *const T as usize;
-    // println!("orig addr: {:#x}", o);
-    let mut offset: usize = 0;
-    for (start_page, num_pages) in heap_regions {
-        let start_page = *start_page as *const PhysPage4KiB as usize;
-        let end_page = start_page + 0x1000 * ((*num_pages) - 1);
-        let o_page = o & 0xffff_ffff_ffff_f000;
-        if o_page >= start_page && o_page <= end_page {
-            // in this region
-            offset += o_page - start_page;
-            break;
+    // Maps `size` bytes at the heap end, from one physical run if there is
+    // one, else with whatever frames are free since only the virtual side has
+    // to be contiguous. Undoes everything on failure.
+    unsafe fn map_frames(&mut self, size: usize) -> bool {
+        let frame_alloc = &mut *self.frame_alloc;
+        let pml4 = &mut *self.pml4;
+        let flags = PageFlags::WRITABLE | PageFlags::NO_EXECUTE;
+        let pages = size / 0x1000;
+
+        // a single run only takes up one region
+        if let Some(run) = frame_alloc.allocate_contiguous(pages, 1) {
+            if !add_heap_region(run, size) {
+                frame_alloc.deallocate_contiguous(run, pages);
+                return false;
+            }
+            for offset in (0..size).step_by(0x1000) {
+                pml4.map_frame_4k(run + offset, self.end + offset, flags);
+            }
+            return true;
         }
-        offset += num_pages * 0x1000;
+
+        // new frames only extend the last region or come after it
+        let (saved_len, saved_last) = {
+            let heap_regions = HEAP_REGIONS.lock();
+            (heap_regions.len, heap_regions.regions[heap_regions.len - 1])
+        };
+        for offset in (0..size).step_by(0x1000) {
+            let frame = match frame_alloc.allocate_frame() {
+                Some(frame) if add_heap_region(frame, 0x1000) => frame,
+                frame => {
+                    if let Some(frame) = frame {
+                        frame_alloc.deallocate(frame);
+                    }
+                    // only the entries are cleared, the tables stay reserved.
+                    // They are shared by every address space, so flushing
+                    // from whichever one is active is enough.
+                    for undo in (0..offset).step_by(0x1000) {
+                        let vaddr = self.end + undo;
+                        let frame = pml4.translate(vaddr).expect("heap page not mapped");
+                        pml4.clear_mapping(vaddr);
+                        frame_alloc.deallocate(frame);
+                    }
+                    let mut heap_regions = HEAP_REGIONS.lock();
+                    heap_regions.len = saved_len;
+                    heap_regions.regions[saved_len - 1] = saved_last;
+                    return false;
+                }
+            };
+            pml4.map_frame_4k(frame, self.end + offset, flags);
+        }
+        true
     }
-    o = HEAP_START + offset + (o & 0xfff);
+}
 
-    // println!("new addr: {:#x}", o);
-    &mut (*(o as *mut T))
+/// Lets the heap grow once it runs out instead of failing
+/// # Safety
+/// `memory` must stay where it is for as long as the kernel runs
+pub unsafe fn enable_heap_growth(memory: &mut KernelMemory) {
+    ALLOCATOR.lock().enable_growth(HeapGrowth {
+        frame_alloc: &mut memory.frame_alloc,
+        pml4: memory.pml4 as *mut PML4,
+        end: HEAP_START + HEAP_SIZE,
+    });
 }
 
 /// # Safety
@@ -1,4 +1,4 @@
-use super::Locked;
+use super::{assert_not_growing, HeapGrowth, Locked};
 use crate::alloc::vec::Vec;
 use crate::memory::heap::linked_list_alloc::LinkedListAllocator;
 use crate::memory::page_table::PhysPage4KiB;
@@ -19,6 +19,8 @@ pub struct BlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: LinkedListAllocator,
     pub total_memory: u64,
+    // set once the heap can be grown
+    growth: Option<HeapGrowth>,
 }
 
 impl BlockAllocator {
@@ -28,6 +30,7 @@ impl BlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: LinkedListAllocator::new(),
             total_memory: 0,
+            growth: None,
         }
     }
 
@@ -120,8 +123,26 @@ impl BlockAllocator {
         self.total_memory += heap_size as u64;
     }
 
+    pub fn enable_growth(&mut self, growth: HeapGrowth) {
+        self.growth = Some(growth);
+    }
+
     unsafe fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        self.fallback_allocator.alloc(layout)
+        let ptr = self.fallback_allocator.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        let Some(growth) = self.growth.as_mut() else {
+            return ptr;
+        };
+        // out of heap, leave room for alignment and a free list node after it
+        match growth.grow(layout.size() + layout.align() + 0x40) {
+            Some((start, size)) => {
+                self.extend(start, size);
+                self.fallback_allocator.alloc(layout)
+            }
+            None => ptr,
+        }
     }
 
     // TODO: still need to do relocation here
@@ -154,6 +175,7 @@ fn list_index(layout: &Layout) -> Option<usize> {
 // Think about how this handles non aligned allocations
 unsafe impl GlobalAlloc for Locked<BlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        assert_not_growing();
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
@@ -179,6 +201,7 @@ unsafe impl GlobalAlloc for Locked<BlockAllocator> {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        assert_not_growing();
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
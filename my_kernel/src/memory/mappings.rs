@@ -1,7 +1,9 @@
 use crate::bootloader_structs::BootInfo;
 use crate::elf::ProgHeaderEntry;
-use crate::memory::heap::{HEAP_SIZE, HEAP_START};
-use crate::memory::page_table::{PhysPage4KiB, VirtPage4KiB, PML4};
+use crate::memory::frame_allocator::{FrameAllocator, FRAME_TABLE_START};
+use crate::memory::heap::{HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START};
+use crate::memory::page_table::{PageFlags, PhysPage4KiB, VirtPage4KiB, PML4};
+use crate::memory::phys_map::{PHYS_MAP_MAX_SIZE, PHYS_MAP_START};
 
 use alloc::vec::Vec;
 use core::convert::TryInto;
@@ -12,17 +14,40 @@ pub fn map_heap(heap_regions: &Vec<(&PhysPage4KiB, usize)>, pml4: &mut PML4) {
     let mut vpage = HEAP_START;
     for (start_page, num_pages) in heap_regions {
         let start_page = *start_page as *const PhysPage4KiB as usize;
-        for page in 0..*num_pages {
-            let phys_page = start_page + (page * 0x1000);
-            unsafe {
-                pml4.map_frame_4k(phys_page, vpage, true, true, None);
-            }
-            vpage += 0x1000;
+        let len = num_pages * 0x1000;
+        unsafe {
+            pml4.map_range(
+                start_page,
+                vpage,
+                len,
+                PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+            );
         }
+        vpage += len;
     }
     if vpage != HEAP_START + HEAP_SIZE {
         panic!("Regions did not cover entire heap");
     }
+    // the heap grows without allocating tables and every process shares them
+    unsafe {
+        pml4.reserve_tables(HEAP_START, HEAP_START + HEAP_MAX_SIZE);
+    }
+}
+
+// Maps `[0, limit)` at `PHYS_MAP_START` with huge pages, kernel only
+pub fn map_physical_memory(pml4: &mut PML4, limit: usize) {
+    let limit = limit.next_multiple_of(0x20_0000);
+    if limit > PHYS_MAP_MAX_SIZE {
+        panic!("Too much physical memory to map: {:#x}", limit);
+    }
+    unsafe {
+        pml4.map_range(
+            0,
+            PHYS_MAP_START,
+            limit,
+            PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        );
+    }
 }
 
 pub fn map_elf_at_current_mapping(boot_info: &BootInfo, pml4: &mut PML4) {
@@ -71,18 +96,14 @@ pub fn map_elf_at_current_mapping(boot_info: &BootInfo, pml4: &mut PML4) {
                 let phys_page = start_page + page * 0x1000;
                 let virt_page = phys_page;
                 unsafe {
-                    pml4.map_frame_4k(phys_page, virt_page, true, true, None);
+                    pml4.map_frame_4k(phys_page, virt_page, entry.page_flags());
                 }
             }
         }
     }
 }
 
-pub fn unmap_elf_at_original_mapping(
-    prog_header_entries: &Vec<ProgHeaderEntry>,
-    pml4: &mut PML4,
-    heap_regions: &Vec<(&PhysPage4KiB, usize)>,
-) {
+pub fn unmap_elf_at_original_mapping(prog_header_entries: &Vec<ProgHeaderEntry>, pml4: &mut PML4) {
     for entry in prog_header_entries {
         if entry.seg_type == 0x1 {
             let start_page = entry.v_addr & 0xfffffffffffff000; // align to 0x1000
@@ -93,18 +114,14 @@ pub fn unmap_elf_at_original_mapping(
                 let phys_page = start_page + page * 0x1000;
                 unsafe {
                     let virt_page = &(*(phys_page as *const VirtPage4KiB));
-                    pml4.unmap_frame_4k(virt_page, Some(heap_regions));
+                    pml4.unmap_frame_4k(virt_page);
                 }
             }
         }
     }
 }
 
-pub fn map_kernel_elf_into_user(
-    prog_header_entries: &Vec<ProgHeaderEntry>,
-    pml4: &mut PML4,
-    heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-) {
+pub fn map_kernel_elf_into_user(prog_header_entries: &Vec<ProgHeaderEntry>, pml4: &mut PML4) {
     for entry in prog_header_entries {
         if entry.seg_type == 0x1 {
             let start_page = entry.v_addr & 0xfffffffffffff000; // align to 0x1000
@@ -115,13 +132,27 @@ pub fn map_kernel_elf_into_user(
                 let phys_page = start_page + page * 0x1000;
                 let virt_page = phys_page - ELF_OLD_BASE + ELF_NEW_BASE;
                 unsafe {
-                    pml4.map_frame_4k(phys_page, virt_page, true, true, Some(heap_regions));
+                    pml4.map_frame_4k(phys_page, virt_page, entry.page_flags());
                 }
             }
         }
     }
 }
 
+// Maps the frame table at `FRAME_TABLE_START`, kernel only
+pub fn map_frame_table(frame_alloc: &FrameAllocator, pml4: &mut PML4) {
+    let (table_phys, pages) = frame_alloc.frame_table_region();
+    for page in 0..pages {
+        unsafe {
+            pml4.map_frame_4k(
+                table_phys + page * 0x1000,
+                FRAME_TABLE_START + page * 0x1000,
+                PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+            );
+        }
+    }
+}
+
 pub const ELF_NEW_BASE: usize = 0xFFFF_8000_0000_0000;
 pub const ELF_OLD_BASE: usize = 0x200000; // maybe dont have this hardcoded
 
@@ -167,25 +198,28 @@ pub fn map_elf_at_new_base(boot_info: &BootInfo, pml4: &mut PML4) {
             let end_page = (entry.v_addr + entry.mem_size as usize) & 0xfffffffffffff000; // align to 0x1000
             let pages = ((end_page - start_page) / 0x1000) + 1;
 
-            for page in 0..pages {
-                let phys_page = start_page + page * 0x1000;
-                let seg_offset = start_page - ELF_OLD_BASE;
-                let virt_page = ELF_NEW_BASE + seg_offset + page * 0x1000;
-                unsafe {
-                    pml4.map_frame_4k(phys_page, virt_page, true, true, None);
-                }
+            let seg_offset = start_page - ELF_OLD_BASE;
+            unsafe {
+                // huge pages where the segment is big and aligned enough
+                pml4.map_range(
+                    start_page,
+                    ELF_NEW_BASE + seg_offset,
+                    pages * 0x1000,
+                    entry.page_flags(),
+                );
             }
         }
     }
 }
 
-pub fn ident_map_vga_buf(
-    pml4: &mut PML4,
-    heap_regions: Option<&Vec<(&'static PhysPage4KiB, usize)>>,
-) {
+pub fn ident_map_vga_buf(pml4: &mut PML4) {
     unsafe {
         let phys_page = 0xb8000;
         let virt_page = phys_page;
-        pml4.map_frame_4k(phys_page, virt_page, true, true, heap_regions);
+        pml4.map_frame_4k(
+            phys_page,
+            virt_page,
+            PageFlags::WRITABLE | PageFlags::NO_EXECUTE | PageFlags::WRITE_COMBINING,
+        );
     }
 }
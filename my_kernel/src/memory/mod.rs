@@ -2,4 +2,5 @@ pub mod frame_allocator;
 pub mod heap;
 pub mod mappings;
 pub mod page_table;
+pub mod phys_map;
 pub mod stack;
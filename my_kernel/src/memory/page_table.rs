@@ -1,20 +1,82 @@
 use alloc::alloc::{Global, Layout};
+use alloc::vec::Vec;
 use core::alloc::Allocator;
 
-use crate::memory::heap::{translate_mut_ref_to_phys, translate_ref_to_virt, HEAP_START};
-use crate::println;
+use crate::cpu::{check_1gib_pages, read_cr3};
+use crate::memory::heap::{phys_to_heap, HEAP_START};
+use crate::memory::phys_map::{phys_to_virt, virt_to_phys, PHYS_MAP_START};
 
-use alloc::vec::Vec;
 use core::arch::asm;
 use core::mem;
+use core::ops::{BitOr, BitOrAssign};
 use core::ptr::NonNull;
 
 // Assumes IA-32e Paging and CR4.PCIDE = 0
-// No support for 1GiB Pages
 
 const PAGE_TABLE_SIZE: usize = 512;
 const RECUR_INDEX: usize = 0x1ff;
 
+const SIZE_2MIB: usize = 0x20_0000;
+const SIZE_1GIB: usize = 0x4000_0000;
+
+// available to software, marks a read only page that is shared after a fork
+const COPY_ON_WRITE: u64 = 1 << 9;
+
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Access and caching bits of an entry, the same at every level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    pub const WRITABLE: PageFlags = PageFlags(1 << 1);
+    pub const USER: PageFlags = PageFlags(1 << 2);
+    // PWT selects PAT entry 1, which `init_pat` sets to write combining
+    pub const WRITE_COMBINING: PageFlags = PageFlags(1 << 3);
+    // PWT and PCD select PAT entry 3, strong uncacheable
+    pub const UNCACHED: PageFlags = PageFlags(0b11 << 3);
+    // last level only, kept across cr3 loads once CR4.PGE is set
+    pub const GLOBAL: PageFlags = PageFlags(1 << 8);
+    // needs EFER.NXE, see `enable_nx`
+    pub const NO_EXECUTE: PageFlags = PageFlags(1 << 63);
+
+    const ALL: PageFlags = PageFlags(0b1_0001_1110 | (1 << 63));
+
+    // tables above the last level allow everything, the last level decides
+    const TABLE: PageFlags = PageFlags(0b110);
+
+    pub const fn empty() -> Self {
+        PageFlags(0)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: PageFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn without(self, other: PageFlags) -> Self {
+        PageFlags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = PageFlags;
+
+    fn bitor(self, other: PageFlags) -> PageFlags {
+        PageFlags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for PageFlags {
+    fn bitor_assign(&mut self, other: PageFlags) {
+        self.0 |= other.0;
+    }
+}
+
 #[derive(Debug)]
 #[repr(align(4096))]
 #[repr(C)]
@@ -24,7 +86,7 @@ pub struct PML4 {
 }
 
 impl PML4 {
-    pub fn new(heap_regions: Option<&Vec<(&'static PhysPage4KiB, usize)>>) -> &'static mut Self {
+    pub fn new() -> &'static mut Self {
         // TODO: Why did i do static ref???
         let ptr = Global.allocate_zeroed(Layout::new::<PML4>());
 
@@ -33,161 +95,318 @@ impl PML4 {
         }
 
         let pml4 = unsafe { &mut *(ptr.unwrap().as_mut_ptr() as *mut PML4) };
-        let mut pml4_recur = unsafe { &mut *(ptr.unwrap().as_mut_ptr() as *mut PDPT) };
-        if let Some(heap_regions) = heap_regions {
-            pml4_recur = unsafe { translate_mut_ref_to_phys(heap_regions, pml4_recur) };
-        }
-        pml4.add(RECUR_INDEX, pml4_recur, true, true);
+        let pml4_recur = unsafe { &*(ptr.unwrap().as_mut_ptr() as *const PDPT) };
+        pml4.add(RECUR_INDEX, pml4_recur, PageFlags::TABLE);
 
         pml4
     }
 
-    pub fn add(&mut self, index: usize, pdpt: &PDPT, writable: bool, user_accessable: bool) {
-        let phys_ptr = pdpt as *const PDPT as usize;
-        if phys_ptr % 0x1000 != 0 || phys_ptr & 0xfff0000000000000 != 0 {
+    pub fn add(&mut self, index: usize, pdpt: &PDPT, flags: PageFlags) {
+        let phys_ptr = virt_to_phys(pdpt as *const PDPT as usize);
+        if !phys_ptr.is_multiple_of(0x1000) || phys_ptr & 0xfff0000000000000 != 0 {
             panic!("PDPT not aligned");
         }
         if index >= self.entries.len() {
             panic!("index out of range");
         }
 
-        let mut data = phys_ptr & 0xffffffffff000;
-        if writable {
-            data |= 0b10;
-        }
-        if user_accessable {
-            data |= 0b100;
-        }
+        let mut data = (phys_ptr as u64 & ADDR_MASK) | flags.bits();
         data |= 0b1; // present
-        self.entries[index].data = data as u64;
+        self.entries[index].data = data;
     }
 
     /// # Safety
     /// `paddr` must be a valid pointer
     /// `vaddr` must be ann unused slot in the PML4
-    pub unsafe fn map_frame_4k(
-        &mut self,
-        paddr: usize,
-        vaddr: usize,
-        writable: bool,
-        user_accessable: bool,
-        heap_regions: Option<&Vec<(&'static PhysPage4KiB, usize)>>,
-    ) {
-        println!("map");
-        if paddr % 0x1000 != 0 {
+    pub unsafe fn map_frame_4k(&mut self, paddr: usize, vaddr: usize, flags: PageFlags) {
+        if !paddr.is_multiple_of(0x1000) {
             panic!("paddr not aligned");
         }
-        if vaddr % 0x1000 != 0 {
+        if !vaddr.is_multiple_of(0x1000) {
             panic!("vaddr not aligned");
         }
-        let (pml4_ind, pdpt_ind, pd_ind, pt_ind) = indicies_of_vaddr(vaddr);
+        let (_, _, _, pt_ind) = indicies_of_vaddr(vaddr);
+        let pt = self.pt_for(vaddr);
+        let pte = &pt.entries[pt_ind];
 
-        let pml4e = &self.entries[pml4_ind];
-        let pdpt = if let Some(phys_pdpt) = pml4e.pdpt() {
-            if let Some(heap_regions) = heap_regions {
-                translate_ref_to_virt(heap_regions, phys_pdpt)
-            } else {
-                phys_pdpt
-            }
+        if pte.present() {
+            panic!("pte already maps a frame - vaddr: {:#x}", vaddr);
         } else {
-            let virt_pdpt = PDPT::new();
-            let phys_pdpt = if let Some(heap_regions) = heap_regions {
-                translate_mut_ref_to_phys(heap_regions, virt_pdpt)
-            } else {
-                &(*virt_pdpt)
-            };
-            self.add(pml4_ind, phys_pdpt, writable, true);
-            virt_pdpt
+            pt.add(pt_ind, paddr, flags);
+        }
+    }
+
+    // Page table covering `vaddr`, the tables above it are created as needed.
+    // They allow everything so only the last level decides the access, which
+    // lets `protect` change it per page.
+    unsafe fn pt_for(&mut self, vaddr: usize) -> &'static mut PT {
+        let (_, _, pd_ind, _) = indicies_of_vaddr(vaddr);
+        let pd = self.pd_for(vaddr);
+        if pd.entries[pd_ind].big_page().is_some() {
+            panic!("2MiB page in the way - vaddr: {:#x}", vaddr);
+        }
+        match pd.entries[pd_ind].pt() {
+            Some(pt) => pt,
+            None => {
+                let pt = PT::new();
+                pd.add(pd_ind, pt, PageFlags::TABLE);
+                pt
+            }
+        }
+    }
+
+    // Page directory covering `vaddr`, the tables above it are created as needed
+    unsafe fn pd_for(&mut self, vaddr: usize) -> &'static mut PD {
+        let (_, pdpt_ind, _, _) = indicies_of_vaddr(vaddr);
+        let pdpt = self.pdpt_for(vaddr);
+        if pdpt.entries[pdpt_ind].huge_page().is_some() {
+            panic!("1GiB page in the way - vaddr: {:#x}", vaddr);
+        }
+        match pdpt.entries[pdpt_ind].pd() {
+            Some(pd) => pd,
+            None => {
+                let pd = PD::new();
+                pdpt.add(pdpt_ind, pd, PageFlags::TABLE);
+                pd
+            }
+        }
+    }
+
+    // Page directory pointer table covering `vaddr`, created if needed
+    unsafe fn pdpt_for(&mut self, vaddr: usize) -> &'static mut PDPT {
+        let (pml4_ind, _, _, _) = indicies_of_vaddr(vaddr);
+        match self.entries[pml4_ind].pdpt() {
+            Some(pdpt) => pdpt,
+            None => {
+                let pdpt = PDPT::new();
+                self.add(pml4_ind, pdpt, PageFlags::TABLE);
+                pdpt
+            }
+        }
+    }
+
+    /// # Safety
+    /// `paddr` must be a valid 2MiB aligned physical address
+    /// `vaddr` must be an unused 2MiB slot in the PML4
+    pub unsafe fn map_frame_2m(&mut self, paddr: usize, vaddr: usize, flags: PageFlags) {
+        if !paddr.is_multiple_of(SIZE_2MIB) {
+            panic!("paddr not aligned");
+        }
+        if !vaddr.is_multiple_of(SIZE_2MIB) {
+            panic!("vaddr not aligned");
+        }
+        let (_, _, pd_ind, _) = indicies_of_vaddr(vaddr);
+        let pd = self.pd_for(vaddr);
+        if pd.entries[pd_ind].present() {
+            panic!("pde already maps a frame - vaddr: {:#x}", vaddr);
+        }
+        pd.add_big_page(pd_ind, paddr, flags);
+    }
+
+    /// # Safety
+    /// `paddr` must be a valid 1GiB aligned physical address
+    /// `vaddr` must be an unused 1GiB slot in the PML4
+    /// The cpu must support 1GiB pages, see `check_1gib_pages`
+    pub unsafe fn map_frame_1g(&mut self, paddr: usize, vaddr: usize, flags: PageFlags) {
+        if !paddr.is_multiple_of(SIZE_1GIB) {
+            panic!("paddr not aligned");
+        }
+        if !vaddr.is_multiple_of(SIZE_1GIB) {
+            panic!("vaddr not aligned");
+        }
+        let (_, pdpt_ind, _, _) = indicies_of_vaddr(vaddr);
+        let pdpt = self.pdpt_for(vaddr);
+        if pdpt.entries[pdpt_ind].present() {
+            panic!("pdpte already maps a frame - vaddr: {:#x}", vaddr);
+        }
+        pdpt.add_huge_page(pdpt_ind, paddr, flags);
+    }
+
+    // True if nothing, not even a table, is in the `size` slot holding `vaddr`
+    fn slot_free(&self, vaddr: usize, size: usize) -> bool {
+        let (pml4_ind, pdpt_ind, pd_ind, _) = indicies_of_vaddr(vaddr);
+        let pdpt = match self.entries[pml4_ind].pdpt() {
+            Some(pdpt) => pdpt,
+            None => return true,
         };
         let pdpte = &pdpt.entries[pdpt_ind];
+        if size == SIZE_1GIB {
+            return !pdpte.present();
+        }
+        match pdpte.pd() {
+            Some(pd) => !pd.entries[pd_ind].present(),
+            None => !pdpte.present(),
+        }
+    }
 
-        let pd = if let Some(phys_pd) = pdpte.pd() {
-            if let Some(heap_regions) = heap_regions {
-                translate_ref_to_virt(heap_regions, phys_pd)
-            } else {
-                phys_pd
+    // Replaces the huge pages holding `vaddr` with tables of smaller pages
+    // mapping the same memory, until `vaddr` is in a 4KiB page
+    unsafe fn split_huge_pages(&mut self, vaddr: usize) {
+        let (pml4_ind, pdpt_ind, pd_ind, _) = indicies_of_vaddr(vaddr);
+        let pdpt = match self.entries[pml4_ind].pdpt() {
+            Some(pdpt) => pdpt,
+            None => return,
+        };
+
+        let pdpte = &pdpt.entries[pdpt_ind];
+        if let Some(page) = pdpte.huge_page() {
+            let flags = pdpte.flags();
+            let pd = PD::new();
+            for index in 0..PAGE_TABLE_SIZE {
+                pd.add_big_page(index, page + index * SIZE_2MIB, flags);
             }
-        } else {
-            let virt_pd = PD::new();
-            let phys_pd = if let Some(heap_regions) = heap_regions {
-                translate_mut_ref_to_phys(heap_regions, virt_pd)
-            } else {
-                &(*virt_pd)
-            };
-            pdpt.add(pdpt_ind, phys_pd, writable, true);
-            virt_pd
+            pdpt.add(pdpt_ind, pd, PageFlags::TABLE);
+            flush_tlb_page(vaddr);
+        }
+
+        let pd = match pdpt.entries[pdpt_ind].pd() {
+            Some(pd) => pd,
+            None => return,
         };
         let pde = &pd.entries[pd_ind];
+        if let Some(page) = pde.big_page() {
+            let page = page as *const PhysPage2MiB as usize;
+            let flags = pde.flags();
+            let pt = PT::new();
+            for index in 0..PAGE_TABLE_SIZE {
+                pt.add(index, page + index * 0x1000, flags);
+            }
+            pd.add(pd_ind, pt, PageFlags::TABLE);
+            flush_tlb_page(vaddr);
+        }
+    }
 
-        let pt = if let Some(phys_pt) = pde.pt() {
-            if let Some(heap_regions) = heap_regions {
-                translate_ref_to_virt(heap_regions, phys_pt)
-            } else {
-                phys_pt
+    /// Creates every table needed to map `[start, end)` without mapping
+    /// anything, so pages can later be mapped there without allocating
+    /// # Safety
+    /// The heap must be able to hold the new tables
+    pub unsafe fn reserve_tables(&mut self, start: usize, end: usize) {
+        for vaddr in (start..end).step_by(SIZE_2MIB) {
+            let (_, _, pd_ind, _) = indicies_of_vaddr(vaddr);
+            // already mapped by a 2MiB page, which needs no table
+            if self.pd_for(vaddr).entries[pd_ind].big_page().is_none() {
+                self.pt_for(vaddr);
             }
-        } else {
-            let virt_pt = PT::new();
-            let phys_pt = if let Some(heap_regions) = heap_regions {
-                translate_mut_ref_to_phys(heap_regions, virt_pt)
+        }
+    }
+
+    // Points the slot holding `vaddr` at the same PDPT as in `other`, so both
+    // see any mappings added there later
+    pub fn share_entry(&mut self, other: &PML4, vaddr: usize) {
+        let (pml4_ind, _, _, _) = indicies_of_vaddr(vaddr);
+        self.entries[pml4_ind].data = other.entries[pml4_ind].data;
+    }
+
+    /// Maps the `len` bytes of physical memory at `paddr` to `vaddr`, with
+    /// 1GiB and 2MiB pages wherever both addresses are aligned for them and
+    /// nothing is mapped there yet
+    /// # Safety
+    /// Same as `map_frame_4k` for every page of the range
+    pub unsafe fn map_range(&mut self, paddr: usize, vaddr: usize, len: usize, flags: PageFlags) {
+        let gib_pages = check_1gib_pages();
+        let mut offset = 0;
+        while offset < len {
+            let (paddr, vaddr) = (paddr + offset, vaddr + offset);
+            let fits = |size: usize| (paddr | vaddr) % size == 0 && len - offset >= size;
+
+            if gib_pages && fits(SIZE_1GIB) && self.slot_free(vaddr, SIZE_1GIB) {
+                self.map_frame_1g(paddr, vaddr, flags);
+                offset += SIZE_1GIB;
+            } else if fits(SIZE_2MIB) && self.slot_free(vaddr, SIZE_2MIB) {
+                self.map_frame_2m(paddr, vaddr, flags);
+                offset += SIZE_2MIB;
             } else {
-                &(*virt_pt)
+                self.map_frame_4k(paddr, vaddr, flags);
+                offset += 0x1000;
+            }
+        }
+    }
+
+    /// Unmaps every 4KiB page mapped in `[start, end)` and frees the tables
+    /// left empty. `f(vaddr, paddr)` is called for each page so the caller
+    /// can decide what happens to the frame. Huge pages are unmapped in 4KiB
+    /// pieces too.
+    pub fn unmap_range(&mut self, start: usize, end: usize, mut f: impl FnMut(usize, usize)) {
+        let mut pages = Vec::new();
+        self.for_each_page(start, end, |vaddr, paddr| pages.push((vaddr, paddr)));
+
+        for (vaddr, paddr) in pages {
+            unsafe {
+                self.unmap_frame_4k(&*(vaddr as *const VirtPage4KiB));
+            }
+            f(vaddr, paddr);
+        }
+    }
+
+    /// Changes the access of every 4KiB page mapped in `[start, end)`
+    /// Huge pages in the range are split into 4KiB pages first.
+    /// Copy on write pages stay read only until a write fault copies them.
+    /// Returns false if a page of the range is not mapped, the others are
+    /// changed anyway.
+    pub fn protect(&mut self, start: usize, end: usize, flags: PageFlags) -> bool {
+        let mut all_mapped = true;
+        for vaddr in (start & !0xfff..end).step_by(0x1000) {
+            unsafe {
+                self.split_huge_pages(vaddr);
+            }
+            let pte = match self.pte_mut(vaddr) {
+                Some(pte) if pte.present() => pte,
+                _ => {
+                    all_mapped = false;
+                    continue;
+                }
             };
-            pd.add(pd_ind, phys_pt, writable, true);
-            virt_pt
-        };
-        let pte = &pt.entries[pt_ind];
+            let mut flags = flags;
+            if pte.copy_on_write() {
+                flags = flags.without(PageFlags::WRITABLE);
+            }
+            pte.data = (pte.data & (ADDR_MASK | COPY_ON_WRITE | 0b1)) | flags.bits();
+            flush_tlb_page(vaddr);
+        }
+        all_mapped
+    }
 
-        if pte.present() {
-            panic!("pte already maps a frame - vaddr: {:#x}", vaddr);
-        } else {
-            pt.add(pt_ind, paddr, writable, user_accessable);
+    /// Physical address behind `vaddr`, None if it is not mapped
+    pub fn translate(&self, vaddr: usize) -> Option<usize> {
+        if !is_canonical(vaddr) {
+            return None;
+        }
+        let (pml4_ind, pdpt_ind, pd_ind, pt_ind) = indicies_of_vaddr(vaddr);
+        let pdpt = self.entries[pml4_ind].pdpt()?;
+        let pdpte = &pdpt.entries[pdpt_ind];
+        if let Some(page) = pdpte.huge_page() {
+            return Some(page + (vaddr & (SIZE_1GIB - 1)));
+        }
+        let pd = pdpte.pd()?;
+        let pde = &pd.entries[pd_ind];
+        if let Some(page) = pde.big_page() {
+            return Some(page as *const PhysPage2MiB as usize + (vaddr & (SIZE_2MIB - 1)));
         }
+        let page = pde.pt()?.entries[pt_ind].page()?;
+        Some(page as *const PhysPage4KiB as usize + (vaddr & 0xfff))
     }
 
-    pub fn unmap_frame_4k(
-        &mut self,
-        vaddr: &VirtPage4KiB,
-        heap_regions: Option<&Vec<(&PhysPage4KiB, usize)>>,
-    ) -> &'static PhysPage4KiB {
-        println!("unmap");
+    /// Unmaps the page at `vaddr` and frees the tables left empty
+    /// A huge page holding it is split first, so the rest stays mapped
+    /// The frame is returned, not freed
+    pub fn unmap_frame_4k(&mut self, vaddr: &VirtPage4KiB) -> &'static PhysPage4KiB {
         let vaddr = vaddr as *const VirtPage4KiB as usize;
-        if vaddr % 0x1000 != 0 {
+        if !vaddr.is_multiple_of(0x1000) {
             panic!("vaddr not aligned");
         }
+        unsafe {
+            self.split_huge_pages(vaddr);
+        }
         let (pml4_ind, pdpt_ind, pd_ind, pt_ind) = indicies_of_vaddr(vaddr);
 
         let pml4e = &mut self.entries[pml4_ind];
-        let pdpt = if let Some(phys_pdpt) = pml4e.pdpt() {
-            if let Some(heap_regions) = heap_regions {
-                unsafe { translate_ref_to_virt(heap_regions, phys_pdpt) }
-            } else {
-                phys_pdpt
-            }
-        } else {
-            panic!("No pdpt for this vaddr");
-        };
+        let pdpt = pml4e.pdpt().expect("No pdpt for this vaddr");
         let pdpte = &mut pdpt.entries[pdpt_ind];
 
-        let pd = if let Some(phys_pd) = pdpte.pd() {
-            if let Some(heap_regions) = heap_regions {
-                unsafe { translate_ref_to_virt(heap_regions, phys_pd) }
-            } else {
-                phys_pd
-            }
-        } else {
-            panic!("No pd for this vaddr");
-        };
+        let pd = pdpte.pd().expect("No pd for this vaddr");
         let pde = &mut pd.entries[pd_ind];
 
-        let pt = if let Some(phys_pt) = pde.pt() {
-            if let Some(heap_regions) = heap_regions {
-                unsafe { translate_ref_to_virt(heap_regions, phys_pt) }
-            } else {
-                phys_pt
-            }
-        } else {
-            panic!("No pt for this vaddr");
-        };
+        let pt = pde.pt().expect("No pt for this vaddr");
         let pte = &mut pt.entries[pt_ind];
 
         let frame = if let Some(frame) = pte.page() {
@@ -197,6 +416,7 @@ impl PML4 {
         };
 
         pte.clear();
+        flush_tlb_page(vaddr);
 
         // check if we should remove a pt
         for pte in pt.entries.iter() {
@@ -206,10 +426,8 @@ impl PML4 {
         }
 
         pde.clear();
-        if let Some(ptr) = NonNull::new(pt as *mut PT as usize as *mut u8) {
-            unsafe {
-                Global.deallocate(ptr, Layout::new::<PT>());
-            }
+        unsafe {
+            free_table(pt);
         }
 
         // check if we shoould remove a pd
@@ -220,10 +438,8 @@ impl PML4 {
         }
 
         pdpte.clear();
-        if let Some(ptr) = NonNull::new(pd as *mut PD as usize as *mut u8) {
-            unsafe {
-                Global.deallocate(ptr, Layout::new::<PD>());
-            }
+        unsafe {
+            free_table(pd);
         }
 
         // check if we shoould remove a pdpt
@@ -234,47 +450,273 @@ impl PML4 {
         }
 
         pml4e.clear();
-        if let Some(ptr) = NonNull::new(pdpt as *mut PDPT as usize as *mut u8) {
-            unsafe {
-                Global.deallocate(ptr, Layout::new::<PDPT>());
-            }
+        unsafe {
+            free_table(pdpt);
         }
 
         unsafe { &(*(frame as *const PhysPage4KiB)) }
     }
 
-    pub fn get_pdpt_recursive(
-        &self,
-        index: usize,
-        heap_phys_regions: &Vec<(&PhysPage4KiB, usize)>,
-    ) -> &'static mut PDPT {
+    /// Returns true if `vaddr` is mapped and reachable from ring 3,
+    /// and writable from it if `write` is set
+    /// Walks the tables through their heap mapping
+    pub fn user_accessible(&self, vaddr: usize, write: bool) -> bool {
+        if !is_canonical(vaddr) {
+            return false;
+        }
+        let (pml4_ind, pdpt_ind, pd_ind, pt_ind) = indicies_of_vaddr(vaddr);
+
+        let pml4e = &self.entries[pml4_ind];
+        let pdpt = match pml4e.pdpt() {
+            Some(pdpt) if pml4e.user_accessable() && (!write || pml4e.writable()) => pdpt,
+            _ => return false,
+        };
+
+        let pdpte = &pdpt.entries[pdpt_ind];
+        if pdpte.huge_page().is_some() {
+            return pdpte.user_accessable() && (!write || pdpte.writable());
+        }
+        let pd = match pdpte.pd() {
+            Some(pd) if pdpte.user_accessable() && (!write || pdpte.writable()) => pd,
+            _ => return false,
+        };
+
+        let pde = &pd.entries[pd_ind];
+        if pde.big_page().is_some() {
+            return pde.user_accessable() && (!write || pde.writable());
+        }
+        let pt = match pde.pt() {
+            Some(pt) if pde.user_accessable() && (!write || pde.writable()) => pt,
+            _ => return false,
+        };
+
+        let pte = &pt.entries[pt_ind];
+        pte.present() && pte.user_accessable() && (!write || pte.writable())
+    }
+
+    /// Returns true if every page in `[vaddr, vaddr + len)` is reachable from ring 3
+    /// with the access `write` asks for
+    pub fn user_range_accessible(&self, vaddr: usize, len: usize, write: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let end = match vaddr.checked_add(len - 1) {
+            Some(end) => end,
+            None => return false,
+        };
+        let start_page = vaddr & 0xffff_ffff_ffff_f000;
+        let end_page = end & 0xffff_ffff_ffff_f000;
+        (start_page..=end_page)
+            .step_by(0x1000)
+            .all(|page| self.user_accessible(page, write))
+    }
+
+    /// Calls `f(vaddr, paddr)` for every 4KiB page mapped in `[start, end)`,
+    /// huge pages are reported as the 4KiB pages they are made of
+    /// Walks the tables through their heap mapping
+    pub fn for_each_page(&self, start: usize, end: usize, mut f: impl FnMut(usize, usize)) {
+        for (pml4_ind, pml4e) in self.entries.iter().enumerate() {
+            if pml4_ind == RECUR_INDEX || !overlaps(pml4_ind << 39, 1 << 39, start, end) {
+                continue;
+            }
+            let pdpt = match pml4e.pdpt() {
+                Some(pdpt) => pdpt,
+                None => continue,
+            };
+            for (pdpt_ind, pdpte) in pdpt.entries.iter().enumerate() {
+                let pdpt_base = (pml4_ind << 39) | (pdpt_ind << 30);
+                if !overlaps(pdpt_base, 1 << 30, start, end) {
+                    continue;
+                }
+                if let Some(page) = pdpte.huge_page() {
+                    for_each_piece(pdpt_base, SIZE_1GIB, page, start, end, &mut f);
+                    continue;
+                }
+                let pd = match pdpte.pd() {
+                    Some(pd) => pd,
+                    None => continue,
+                };
+                for (pd_ind, pde) in pd.entries.iter().enumerate() {
+                    let pd_base = pdpt_base | (pd_ind << 21);
+                    if !overlaps(pd_base, 1 << 21, start, end) {
+                        continue;
+                    }
+                    if let Some(page) = pde.big_page() {
+                        let page = page as *const PhysPage2MiB as usize;
+                        for_each_piece(pd_base, SIZE_2MIB, page, start, end, &mut f);
+                        continue;
+                    }
+                    let pt = match pde.pt() {
+                        Some(pt) => pt,
+                        None => continue,
+                    };
+                    for (pt_ind, pte) in pt.entries.iter().enumerate() {
+                        let vaddr = sign_extend(pd_base | (pt_ind << 12));
+                        if vaddr < start || vaddr >= end {
+                            continue;
+                        }
+                        if let Some(page) = pte.page() {
+                            f(vaddr, page as *const PhysPage4KiB as usize);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Frees every table below this PML4 and then the PML4 itself
+    /// The frames mapped by it are left alone
+    /// # Safety
+    /// Must not be the active page table and must not be used afterwards
+    pub unsafe fn free(&mut self) {
+        let (heap_ind, _, _, _) = indicies_of_vaddr(HEAP_START);
+        let (phys_map_ind, _, _, _) = indicies_of_vaddr(PHYS_MAP_START);
+        for (pml4_ind, pml4e) in self.entries.iter().enumerate() {
+            // the heap and physical memory tables are shared with the kernel page table
+            if pml4_ind == RECUR_INDEX || pml4_ind == heap_ind || pml4_ind == phys_map_ind {
+                continue;
+            }
+            let pdpt = match pml4e.pdpt() {
+                Some(pdpt) => pdpt,
+                None => continue,
+            };
+            for pdpte in pdpt.entries.iter() {
+                let pd = match pdpte.pd() {
+                    Some(pd) => pd,
+                    None => continue,
+                };
+                for pde in pd.entries.iter() {
+                    if let Some(pt) = pde.pt() {
+                        free_table(pt);
+                    }
+                }
+                free_table(pd);
+            }
+            free_table(pdpt);
+        }
+        Global.deallocate(NonNull::from(self).cast(), Layout::new::<PML4>());
+    }
+
+    // Entry for the 4KiB page holding `vaddr` if all the tables above it exist
+    fn pte_mut(&self, vaddr: usize) -> Option<&'static mut PTE> {
+        if !is_canonical(vaddr) {
+            return None;
+        }
+        let (pml4_ind, pdpt_ind, pd_ind, pt_ind) = indicies_of_vaddr(vaddr);
+        let pdpt = self.entries[pml4_ind].pdpt()?;
+        let pd = pdpt.entries[pdpt_ind].pd()?;
+        let pt = pd.entries[pd_ind].pt()?;
+        Some(&mut pt.entries[pt_ind])
+    }
+
+    /// Flags of the 4KiB page at `vaddr`, None if it is not mapped
+    /// Copy on write pages show up as read only
+    pub fn page_flags(&self, vaddr: usize) -> Option<PageFlags> {
+        match self.pte_mut(vaddr) {
+            Some(pte) if pte.present() => Some(pte.flags()),
+            _ => None,
+        }
+    }
+
+    /// Makes the page at `vaddr` read only until a write fault copies it
+    /// The caller has to flush the TLB if this is the active page table
+    pub fn mark_copy_on_write(&mut self, vaddr: usize) {
+        let pte = self.pte_mut(vaddr).expect("No page to share");
+        pte.data = (pte.data & !0b10) | COPY_ON_WRITE;
+    }
+
+    /// Returns the frame behind `vaddr` if it was marked copy on write
+    pub fn copy_on_write_frame(&self, vaddr: usize) -> Option<usize> {
+        let pte = self.pte_mut(vaddr)?;
+        if pte.copy_on_write() {
+            pte.page().map(|page| page as *const PhysPage4KiB as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Gives write access back to a copy on write page that is no longer shared
+    /// # Safety
+    /// Must be the active page table
+    pub unsafe fn make_writable(&mut self, vaddr: usize) {
+        let pte = self.pte_mut(vaddr).expect("No page to make writable");
+        pte.data = (pte.data | 0b10) & !COPY_ON_WRITE;
+        flush_tlb_page(vaddr);
+    }
+
+    /// Drops the mapping at `vaddr` but keeps the tables so it can be mapped again
+    /// The frame is not freed
+    /// # Safety
+    /// Must be the active page table
+    pub unsafe fn clear_mapping(&mut self, vaddr: usize) {
+        if let Some(pte) = self.pte_mut(vaddr) {
+            pte.clear();
+            flush_tlb_page(vaddr);
+        }
+    }
+
+    pub fn get_pdpt_recursive(&self, index: usize) -> &'static mut PDPT {
         let phys_addr_ptr = indicies_to_vaddr(0x1ff, 0x1ff, 0x1ff, 0x1ff, index);
         // this ^ address will recursively point to the phys addr of the PDPT
         unsafe {
             let phys_addr = *(phys_addr_ptr as *const usize);
-            let pdpt_page = phys_addr & 0xffff_ffff_ffff_f000;
-            let mut offset: usize = 0;
-            for (start_page, num_pages) in heap_phys_regions {
-                let start_page = *start_page as *const PhysPage4KiB as usize;
-                let end_page = start_page + 0x1000 * ((*num_pages) - 1);
-                if pdpt_page >= start_page && pdpt_page <= end_page {
-                    // in this region
-                    offset += pdpt_page - start_page;
-                    let virt_addr = HEAP_START + offset;
-                    return &mut *(virt_addr as *mut PDPT);
-                }
-                offset += num_pages * 0x1000;
-            }
-            panic!("could not find {:#x}", pdpt_page)
+            let pdpt_page = phys_addr & 0x000f_ffff_ffff_f000;
+            &mut *(phys_to_virt(pdpt_page) as *mut PDPT)
         }
     }
 }
 
-fn indicies_of_vaddr(vaddr: usize) -> (usize, usize, usize, usize) {
-    if (vaddr & 0x_8000_0000_0000 == 0x_8000_0000_0000
+// Gives a table reached through the physical memory map back to the heap it
+// was allocated from
+unsafe fn free_table<T>(table: &mut T) {
+    let heap_addr = phys_to_heap(virt_to_phys(table as *mut T as usize));
+    Global.deallocate(
+        NonNull::new_unchecked(heap_addr as *mut u8),
+        Layout::new::<T>(),
+    );
+}
+
+fn is_canonical(vaddr: usize) -> bool {
+    !((vaddr & 0x_8000_0000_0000 == 0x_8000_0000_0000
         && vaddr & 0xffff_8000_0000_0000 != 0xffff_8000_0000_0000)
-        || (vaddr & 0x_8000_0000_0000 == 0 && vaddr & 0xffff_8000_0000_0000 != 0)
-    {
+        || (vaddr & 0x_8000_0000_0000 == 0 && vaddr & 0xffff_8000_0000_0000 != 0))
+}
+
+// Fills in the upper bits of a 48 bit address
+fn sign_extend(vaddr: usize) -> usize {
+    if vaddr & 0x_8000_0000_0000 == 0x_8000_0000_0000 {
+        vaddr | 0xffff_0000_0000_0000
+    } else {
+        vaddr
+    }
+}
+
+// Calls `f` for the 4KiB pieces in `[start, end)` of the huge page of `size`
+// bytes mapping `paddr` at `base`
+fn for_each_piece(
+    base: usize,
+    size: usize,
+    paddr: usize,
+    start: usize,
+    end: usize,
+    f: &mut impl FnMut(usize, usize),
+) {
+    let base = sign_extend(base);
+    let first = start.next_multiple_of(0x1000).max(base);
+    let last = end.min(base.saturating_add(size));
+    for vaddr in (first..last).step_by(0x1000) {
+        f(vaddr, paddr + (vaddr - base));
+    }
+}
+
+// Whether `[base, base + len)` of the 48 bit address space and `[start, end)` intersect
+fn overlaps(base: usize, len: usize, start: usize, end: usize) -> bool {
+    let base = sign_extend(base);
+    base < end && start <= base + (len - 1)
+}
+
+fn indicies_of_vaddr(vaddr: usize) -> (usize, usize, usize, usize) {
+    if !is_canonical(vaddr) {
         panic!(
             "Vaddr not cannonical: {:#x} {:#x}",
             vaddr,
@@ -349,7 +791,9 @@ impl PML4E {
     #[inline(always)]
     pub fn pdpt(&self) -> Option<&'static mut PDPT> {
         if self.present() {
-            unsafe { Some(&mut *(((self.data as usize) & 0xffffffffff000) as *mut PDPT)) }
+            unsafe {
+                Some(&mut *(phys_to_virt((self.data as usize) & 0xffffffffff000) as *mut PDPT))
+            }
         } else {
             None
         }
@@ -375,24 +819,27 @@ impl PDPT {
         unsafe { &mut *(ptr.unwrap().as_mut_ptr() as *mut PDPT) }
     }
 
-    pub fn add(&mut self, index: usize, pd: &PD, writable: bool, user_accessable: bool) {
-        let phys_ptr = pd as *const PD as usize;
-        if phys_ptr % 0x1000 != 0 || phys_ptr & 0xfff0000000000000 != 0 {
+    /// # Safety
+    /// `page` must be a 1GiB aligned physical address that is not in use
+    pub unsafe fn add_huge_page(&mut self, index: usize, page: usize, flags: PageFlags) {
+        let mut data = (page as u64 & 0xfffffc0000000) | flags.bits();
+        data |= 0b10000000; // 1GiB page
+        data |= 0b1; // present
+        self.entries[index].data = data;
+    }
+
+    pub fn add(&mut self, index: usize, pd: &PD, flags: PageFlags) {
+        let phys_ptr = virt_to_phys(pd as *const PD as usize);
+        if !phys_ptr.is_multiple_of(0x1000) || phys_ptr & 0xfff0000000000000 != 0 {
             panic!("PD not aligned");
         }
         if index >= self.entries.len() {
             panic!("index out of range");
         }
 
-        let mut data = phys_ptr & 0xffffffffff000;
-        if writable {
-            data |= 0b10;
-        }
-        if user_accessable {
-            data |= 0b100;
-        }
+        let mut data = (phys_ptr as u64 & ADDR_MASK) | flags.bits();
         data |= 0b1; // present
-        self.entries[index].data = data as u64;
+        self.entries[index].data = data;
     }
 }
 
@@ -432,10 +879,31 @@ impl PDPTE {
         self.data = 0;
     }
 
+    #[inline(always)]
+    pub fn huge_page_enabled(&self) -> bool {
+        self.data & 0b10000000 != 0
+    }
+
+    #[inline(always)]
+    pub fn flags(&self) -> PageFlags {
+        PageFlags(self.data & PageFlags::ALL.bits())
+    }
+
     #[inline(always)]
     pub fn pd(&self) -> Option<&'static mut PD> {
-        if self.present() {
-            unsafe { Some(&mut *(((self.data as usize) & 0xffffffffff000) as *mut PD)) }
+        if self.present() && !self.huge_page_enabled() {
+            unsafe { Some(&mut *(phys_to_virt((self.data as usize) & 0xffffffffff000) as *mut PD)) }
+        } else {
+            None
+        }
+    }
+
+    // Physical address of the 1GiB page mapped here. There is no type for it
+    // as rust can't align one to 1GiB.
+    #[inline(always)]
+    pub fn huge_page(&self) -> Option<usize> {
+        if self.present() && self.huge_page_enabled() {
+            Some((self.data as usize) & 0xfffffc0000000)
         } else {
             None
         }
@@ -461,24 +929,27 @@ impl PD {
         unsafe { &mut *(ptr.unwrap().as_mut_ptr() as *mut PD) }
     }
 
-    pub fn add(&mut self, index: usize, pt: &PT, writable: bool, user_accessable: bool) {
-        let phys_ptr = pt as *const PT as usize;
-        if phys_ptr % 0x1000 != 0 || phys_ptr & 0xfff0000000000000 != 0 {
+    pub fn add(&mut self, index: usize, pt: &PT, flags: PageFlags) {
+        let phys_ptr = virt_to_phys(pt as *const PT as usize);
+        if !phys_ptr.is_multiple_of(0x1000) || phys_ptr & 0xfff0000000000000 != 0 {
             panic!("PT not aligned");
         }
         if index >= self.entries.len() {
             panic!("index out of range");
         }
 
-        let mut data = phys_ptr & 0xffffffffff000;
-        if writable {
-            data |= 0b10;
-        }
-        if user_accessable {
-            data |= 0b100;
-        }
+        let mut data = (phys_ptr as u64 & ADDR_MASK) | flags.bits();
+        data |= 0b1; // present
+        self.entries[index].data = data;
+    }
+
+    /// # Safety
+    /// `page` must be a 2MiB aligned physical address that is not in use
+    pub unsafe fn add_big_page(&mut self, index: usize, page: usize, flags: PageFlags) {
+        let mut data = (page as u64 & 0xfffffffe00000) | flags.bits();
+        data |= 0b10000000; // 2MiB page
         data |= 0b1; // present
-        self.entries[index].data = data as u64;
+        self.entries[index].data = data;
     }
 }
 
@@ -537,11 +1008,18 @@ impl PDE {
         self.data = 0;
     }
 
+    #[inline(always)]
+    pub fn flags(&self) -> PageFlags {
+        PageFlags(self.data & PageFlags::ALL.bits())
+    }
+
     #[inline(always)]
     pub fn pt(&self) -> Option<&'static mut PT> {
         if self.present() {
             if !self.big_page_enabled() {
-                unsafe { Some(&mut *(((self.data as usize) & 0xffffffffff000) as *mut PT)) }
+                unsafe {
+                    Some(&mut *(phys_to_virt((self.data as usize) & 0xffffffffff000) as *mut PT))
+                }
             } else {
                 None
             }
@@ -589,24 +1067,18 @@ impl PT {
 
     /// # Safety
     /// `page` must be a valid physical page that is not currently in use
-    pub unsafe fn add(&mut self, index: usize, page: usize, writable: bool, user_accessable: bool) {
+    pub unsafe fn add(&mut self, index: usize, page: usize, flags: PageFlags) {
         let phys_ptr = page;
-        if phys_ptr % 0x1000 != 0 || phys_ptr & 0xfff0000000000000 != 0 {
+        if !phys_ptr.is_multiple_of(0x1000) || phys_ptr & 0xfff0000000000000 != 0 {
             panic!("Page not aligned {:#x}", phys_ptr);
         }
         if index >= self.entries.len() {
             panic!("index out of range");
         }
 
-        let mut data = phys_ptr & 0xffffffffff000;
-        if writable {
-            data |= 0b10;
-        }
-        if user_accessable {
-            data |= 0b100;
-        }
+        let mut data = (phys_ptr as u64 & ADDR_MASK) | flags.bits();
         data |= 0b1; // present
-        self.entries[index].data = data as u64;
+        self.entries[index].data = data;
     }
 }
 
@@ -666,6 +1138,16 @@ impl PTE {
         self.data = 0;
     }
 
+    #[inline(always)]
+    pub fn flags(&self) -> PageFlags {
+        PageFlags(self.data & PageFlags::ALL.bits())
+    }
+
+    #[inline(always)]
+    fn copy_on_write(&self) -> bool {
+        self.data & COPY_ON_WRITE != 0
+    }
+
     #[inline(always)]
     pub fn page(&self) -> Option<&'static PhysPage4KiB> {
         if self.present() {
@@ -684,14 +1166,19 @@ impl PTE {
 /// Possibly create another function that can be used with PML4's that have
 /// been created on the heap
 pub unsafe fn current_page_table() -> &'static PML4 {
-    let mut cr3: usize;
-    asm!("mov {}, cr3", out(reg) cr3);
-    &*(cr3 as *const PML4) as &PML4
+    &*(phys_to_virt(read_cr3()) as *const PML4)
+}
+
+// Drops a stale translation of `vaddr` after its entry changed
+pub fn flush_tlb_page(vaddr: usize) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) vaddr);
+    }
 }
 
 pub fn set_page_table(pml4: &PML4) {
     let ptr = pml4 as *const PML4 as usize;
-    if ptr % 0x1000 != 0 {
+    if !ptr.is_multiple_of(0x1000) {
         panic!("PML4 not aligned");
     }
     unsafe {
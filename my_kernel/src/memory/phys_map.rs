@@ -0,0 +1,41 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::page_table::current_page_table;
+
+// All of physical memory is mapped here from phase 2 on
+pub const PHYS_MAP_START: usize = 0xFFFF_C000_0000_0000;
+// one PML4 slot, so every address space can share it
+pub const PHYS_MAP_MAX_SIZE: usize = 0x80_0000_0000;
+
+// Added to a physical address to reach it. Stays 0 while phase 1 runs on the
+// identity map of the bootloader.
+static PHYS_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Switches `phys_to_virt` over to the physical memory map
+/// # Safety
+/// The active page table must map physical memory at `PHYS_MAP_START`
+pub unsafe fn enable_phys_map() {
+    PHYS_OFFSET.store(PHYS_MAP_START, Ordering::Relaxed);
+}
+
+// Where the kernel can access `paddr`
+#[inline(always)]
+pub fn phys_to_virt(paddr: usize) -> usize {
+    paddr + PHYS_OFFSET.load(Ordering::Relaxed)
+}
+
+// Physical address behind a kernel address, found by walking the active page
+// table. Panics if `vaddr` is not mapped.
+pub fn virt_to_phys(vaddr: usize) -> usize {
+    let offset = PHYS_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return vaddr;
+    }
+    if (PHYS_MAP_START..PHYS_MAP_START + PHYS_MAP_MAX_SIZE).contains(&vaddr) {
+        return vaddr - PHYS_MAP_START;
+    }
+    match unsafe { current_page_table() }.translate(vaddr) {
+        Some(paddr) => paddr,
+        None => panic!("{:#x} is not mapped", vaddr),
+    }
+}
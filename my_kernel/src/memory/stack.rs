@@ -1,74 +1,35 @@
-use crate::memory::frame_allocator::LinkedListFrameAllocator;
-use crate::memory::page_table::{PhysPage4KiB, VirtPage4KiB, PML4};
-use alloc::vec::Vec;
+use crate::memory::frame_allocator::{FrameAllocator, FRAMES_PER_2MIB};
+use crate::memory::page_table::{PageFlags, PhysPage4KiB, PML4};
 
 pub const STACK_SIZE: usize = 2048 * 1024; // 2048 KiB, this should always be a multiple of 4KiB
 pub const KERN_STACK_TOP: usize = 0xFFFF_F000_0000_0000;
 
+// user stacks are reserved below here and mapped as they grow
 pub const USER_STACK_TOP: usize = 0xFFFF_E000_0000_0000;
 
+// kernel stack of each process, these live on the heap
+pub const PROC_KERN_STACK_SIZE: usize = 64 * 1024;
+
 pub fn create_new_stack_and_map(
-    frame_alloc: &mut LinkedListFrameAllocator,
+    frame_alloc: &mut FrameAllocator,
     pml4: &mut PML4,
 ) -> &'static PhysPage4KiB {
     let kern_top_page = KERN_STACK_TOP & 0xfffffffffffff000;
     let kern_bot_page = (KERN_STACK_TOP - STACK_SIZE) & 0xfffffffffffff000;
 
-    let mut last_page: usize = 0x0;
-    let mut first_page: usize = 0x0;
-    for vpage in (kern_bot_page..kern_top_page).step_by(0x1000) {
-        if let Some(new_page) = frame_alloc.allocate() {
-            let new_page = new_page as *const PhysPage4KiB as usize;
-            if last_page != 0 && last_page + 0x1000 != new_page {
-                panic!(
-                    "pages were not contiguous | last: {:#x} cur: {:#x}",
-                    last_page, new_page
-                );
-            }
-            if first_page == 0 {
-                first_page = new_page;
-            }
-            last_page = new_page;
-
-            unsafe {
-                pml4.map_frame_4k(new_page, vpage, true, true, None);
-            }
-        } else {
-            panic!("Out of Pages");
-        }
+    // the stack is mapped back to front from its last frame so it has to be
+    // contiguous, and aligned so it fits in huge pages
+    let first_page = frame_alloc
+        .allocate_contiguous(STACK_SIZE / 0x1000, FRAMES_PER_2MIB)
+        .expect("Out of Pages");
+    unsafe {
+        pml4.map_range(
+            first_page,
+            kern_bot_page,
+            kern_top_page - kern_bot_page,
+            PageFlags::WRITABLE | PageFlags::NO_EXECUTE,
+        );
     }
+    let last_page = first_page + STACK_SIZE - 0x1000;
     unsafe { &*(last_page as *const PhysPage4KiB) }
 }
-
-pub fn create_new_user_stack_and_map(
-    frame_alloc: &mut LinkedListFrameAllocator,
-    kern_pml4: &mut PML4,
-    user_pml4: &mut PML4,
-    heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-) -> &'static VirtPage4KiB {
-    let user_top_page = USER_STACK_TOP & 0xfffffffffffff000;
-    let user_bot_page = (USER_STACK_TOP - STACK_SIZE) & 0xfffffffffffff000;
-
-    let mut last_page: usize = 0x0;
-    let mut first_page: usize = 0x0;
-    for vpage in (user_bot_page..user_top_page).step_by(0x1000) {
-        if let Some(new_page) =
-            frame_alloc.allocate_and_map_for_other(kern_pml4, user_pml4, vpage, heap_regions)
-        {
-            let new_page = new_page as *const VirtPage4KiB as usize;
-            if last_page != 0 && last_page + 0x1000 != new_page {
-                panic!(
-                    "pages were not contiguous | last: {:#x} cur: {:#x}",
-                    last_page, new_page
-                );
-            }
-            if first_page == 0 {
-                first_page = new_page;
-            }
-            last_page = new_page;
-        } else {
-            panic!("Out of Pages");
-        }
-    }
-    unsafe { &*(last_page as *const VirtPage4KiB) }
-}
@@ -0,0 +1,503 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem;
+
+use crate::elf_loader::{ElfLoader, ELF_STAGING_AREA, USER_PROG_AREA};
+use crate::fs::vfs::{FileTable, MAX_OPEN_FILES};
+use crate::gdt::{USER_CODE_SEL, USER_DATA_SEL};
+use crate::interrupts::{interrupt_return, InterruptStackFrame, TrapFrame};
+use crate::kernel_data::{KernelMemory, KERNEL_DATA, KERNEL_MEMORY};
+use crate::memory::frame_allocator::FrameOwner;
+use crate::memory::mappings::map_kernel_elf_into_user;
+use crate::memory::page_table::{current_page_table, PageFlags, VirtPage4KiB, PML4};
+use crate::memory::phys_map::virt_to_phys;
+use crate::memory::stack::{PROC_KERN_STACK_SIZE, STACK_SIZE, USER_STACK_TOP};
+use crate::process::region::{RegionKind, RegionList};
+
+pub mod region;
+pub mod scheduler;
+
+// interrupt enable flag and the reserved bit that is always set
+const USER_RFLAGS: u64 = 0x202;
+
+// callee saved registers pushed by `switch_context`
+const SWITCH_FRAME_REGS: usize = 6;
+
+// The heap starts out empty here and grows up with `set_brk`
+pub const USER_HEAP_START: usize = 0x0000_3000_0000_0000;
+pub const USER_HEAP_MAX: usize = 0x4000_0000; // 1 GiB
+
+// Parts of the address space whose frames belong to the process alone, the
+// heap sits inside the first one. Everything else in its page tables is
+// shared with the kernel.
+const USER_OWNED_RANGES: [(usize, usize); 2] = [
+    (USER_PROG_AREA, ELF_STAGING_AREA),
+    (USER_STACK_TOP - STACK_SIZE, USER_STACK_TOP),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    // blocked until the child with this pid exits
+    Waiting(usize),
+    // exited with this code, kept until the parent waits for it
+    Zombie(i32),
+}
+
+pub struct Process {
+    pub pid: usize,
+    // None once the parent has exited, nobody will wait for it then
+    pub parent: Option<usize>,
+    pub state: ProcessState,
+    pub pml4: &'static mut PML4,
+    // physical address of `pml4`
+    pub cr3: usize,
+    // used for interrupts and syscalls while this process is running
+    kernel_stack: Box<[u8]>,
+    // kernel stack pointer saved by `switch_context` while not running
+    pub saved_rsp: usize,
+    pub files: FileTable,
+    // user memory that is backed on first touch
+    pub regions: RegionList,
+    // end of the heap as the program sees it, the heap region ends on the
+    // page boundary above
+    pub brk: usize,
+}
+
+impl Process {
+    pub fn new(
+        pid: usize,
+        parent: Option<usize>,
+        entry_point: u64,
+        pml4: &'static mut PML4,
+        cr3: usize,
+    ) -> Self {
+        Process::with_frame(pid, parent, initial_frame(entry_point, cr3), pml4, cr3)
+    }
+
+    // A process that enters user mode with the registers in `frame`
+    fn with_frame(
+        pid: usize,
+        parent: Option<usize>,
+        frame: TrapFrame,
+        pml4: &'static mut PML4,
+        cr3: usize,
+    ) -> Self {
+        let kernel_stack = vec![0u8; PROC_KERN_STACK_SIZE].into_boxed_slice();
+        let stack_top = (kernel_stack.as_ptr() as usize + kernel_stack.len()) & !0xf;
+
+        // Lay out the kernel stack as if the process was interrupted in user mode
+        // and then switched away from. The first switch to it pops the zeroed
+        // callee saved registers and returns into `interrupt_return`, which
+        // irets with `frame`.
+        let frame_addr = stack_top - mem::size_of::<TrapFrame>();
+        let ret_addr = frame_addr - mem::size_of::<usize>();
+        let saved_rsp = ret_addr - SWITCH_FRAME_REGS * mem::size_of::<usize>();
+        unsafe {
+            *(frame_addr as *mut TrapFrame) = frame;
+            *(ret_addr as *mut usize) = interrupt_return as *const () as usize;
+        }
+
+        Process {
+            pid,
+            parent,
+            state: ProcessState::Ready,
+            pml4,
+            cr3,
+            kernel_stack,
+            saved_rsp,
+            files: FileTable::new(),
+            regions: initial_regions(),
+            brk: USER_HEAP_START,
+        }
+    }
+
+    pub fn kernel_stack_top(&self) -> usize {
+        (self.kernel_stack.as_ptr() as usize + self.kernel_stack.len()) & !0xf
+    }
+
+    // Returns true if `[addr, addr + len)` lies inside memory this process
+    // owns. The kernel is mapped in every process too, so pointers from a
+    // syscall have to pass this before the page flags mean anything.
+    pub fn owns_range(&self, addr: usize, len: usize) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        USER_OWNED_RANGES
+            .iter()
+            .any(|&(start, stop)| start <= addr && end <= stop)
+    }
+
+    // User registers saved by the syscall entry, only meaningful while this
+    // process is inside a syscall. Changes are picked up on the way out.
+    pub fn syscall_frame(&mut self) -> &mut TrapFrame {
+        let frame_addr = self.kernel_stack_top() - mem::size_of::<TrapFrame>();
+        unsafe { &mut *(frame_addr as *mut TrapFrame) }
+    }
+
+    // Duplicates this process, which must be inside a syscall, as `pid`. The
+    // child returns 0 from the same syscall. Every frame the parent owns is
+    // shared, writable ones copy on write so the first write from either side
+    // copies it.
+    pub fn fork(&mut self, pid: usize, memory: &mut KernelMemory) -> Process {
+        let child_pml4 =
+            ElfLoader::new_user_pml4(&memory.frame_alloc, memory.pml4, memory.stack_phys);
+        map_kernel_elf_into_user(&memory.prog_header_entries, child_pml4);
+
+        for (start, end) in USER_OWNED_RANGES {
+            let mut pages = Vec::new();
+            self.pml4
+                .for_each_page(start, end, |vaddr, paddr| pages.push((vaddr, paddr)));
+            for (vaddr, paddr) in pages {
+                let flags = self.pml4.page_flags(vaddr).expect("No page to share");
+                unsafe {
+                    child_pml4.map_frame_4k(paddr, vaddr, flags);
+                }
+                if flags.contains(PageFlags::WRITABLE)
+                    || self.pml4.copy_on_write_frame(vaddr).is_some()
+                {
+                    self.pml4.mark_copy_on_write(vaddr);
+                    child_pml4.mark_copy_on_write(vaddr);
+                }
+                memory.frame_alloc.share(paddr);
+            }
+        }
+        // the parent page table is reloaded on the way back to user mode,
+        // which flushes the writable translations
+
+        let cr3 = virt_to_phys(child_pml4 as *const _ as usize);
+        let parent_frame = self.syscall_frame();
+        let frame = TrapFrame {
+            cr3: cr3 as u64,
+            rax: 0,
+            ..*parent_frame
+        };
+
+        let mut child = Process::with_frame(pid, Some(self.pid), frame, child_pml4, cr3);
+        child.files = self.files.clone();
+        child.regions = self.regions.clone();
+        child.brk = self.brk;
+        child
+    }
+
+    // Replaces the program of this process, which must be inside a syscall,
+    // with the ELF in `file_data`. Open files are kept. Returns false and
+    // leaves the process alone if it is not a valid ELF.
+    pub fn exec(&mut self, file_data: Vec<u8>, memory: &mut KernelMemory) -> bool {
+        let (entry_point, pml4, cr3) = match load_address_space(file_data, memory) {
+            Some(loaded) => loaded,
+            None => return false,
+        };
+        self.free_address_space(memory);
+        self.pml4 = pml4;
+        self.cr3 = cr3;
+        self.regions = initial_regions();
+        self.brk = USER_HEAP_START;
+        *self.syscall_frame() = initial_frame(entry_point, cr3);
+        true
+    }
+
+    // Backs the page holding `vaddr` with a zeroed frame if it is inside a
+    // region and not mapped yet. Returns false if it can't be.
+    // The page table of this process must be the active one.
+    pub fn map_demand_page(&mut self, vaddr: usize, memory: &mut KernelMemory) -> bool {
+        let page = vaddr & !0xfff;
+        if self.regions.find(page).is_none() {
+            return false;
+        }
+        if self.pml4.user_accessible(page, false) {
+            return false;
+        }
+        let flags = PageFlags::WRITABLE | PageFlags::USER | PageFlags::NO_EXECUTE;
+        match memory.frame_alloc.allocate_and_map(self.pml4, page, flags) {
+            Some((virt_page, paddr)) => {
+                memory.frame_alloc.set_owner(paddr, FrameOwner::User);
+                unsafe {
+                    core::ptr::write_bytes(virt_page as *const VirtPage4KiB as *mut u8, 0, 0x1000);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Gives this process its own copy of a copy on write page, or just write
+    // access if nobody else maps it anymore. Returns false if it can't be.
+    // The page table of this process must be the active one.
+    pub fn resolve_copy_on_write(&mut self, vaddr: usize, memory: &mut KernelMemory) -> bool {
+        let page = vaddr & !0xfff;
+        let shared = match self.pml4.copy_on_write_frame(page) {
+            Some(paddr) => paddr,
+            None => return false,
+        };
+        // copy on write pages are only ever made from writable ones
+        let flags = self.pml4.page_flags(page).unwrap() | PageFlags::WRITABLE;
+        if memory.frame_alloc.refcount(shared) == 1 {
+            unsafe {
+                self.pml4.make_writable(page);
+            }
+            return true;
+        }
+
+        let mut copy = vec![0u8; 0x1000];
+        unsafe {
+            core::ptr::copy_nonoverlapping(page as *const u8, copy.as_mut_ptr(), 0x1000);
+            self.pml4.clear_mapping(page);
+        }
+        match memory.frame_alloc.allocate_and_map(self.pml4, page, flags) {
+            Some((virt_page, paddr)) => {
+                memory.frame_alloc.set_owner(paddr, FrameOwner::User);
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        copy.as_ptr(),
+                        virt_page as *const VirtPage4KiB as *mut u8,
+                        0x1000,
+                    );
+                }
+                memory.frame_alloc.release(shared);
+                true
+            }
+            None => {
+                // put the shared frame back so it is released on exit
+                unsafe {
+                    self.pml4.map_frame_4k(shared, page, flags);
+                }
+                self.pml4.mark_copy_on_write(page);
+                false
+            }
+        }
+    }
+
+    // Handles a page fault from this process, `present` and `write` come from
+    // the error code. Returns false if it was a real fault.
+    // The page table of this process must be the active one.
+    pub fn handle_fault(
+        &mut self,
+        vaddr: usize,
+        present: bool,
+        write: bool,
+        memory: &mut KernelMemory,
+    ) -> bool {
+        if !present {
+            self.map_demand_page(vaddr, memory)
+        } else if write {
+            self.resolve_copy_on_write(vaddr, memory)
+        } else {
+            false
+        }
+    }
+
+    // Does what a fault would for every page of `[addr, addr + len)` so the
+    // kernel can access the range without faulting
+    pub fn fault_in_range(
+        &mut self,
+        addr: usize,
+        len: usize,
+        write: bool,
+        memory: &mut KernelMemory,
+    ) {
+        if len == 0 {
+            return;
+        }
+        let last = match addr.checked_add(len - 1) {
+            Some(last) => last,
+            None => return,
+        };
+        unsafe {
+            let prev_cr3 = current_page_table() as *const _ as usize;
+            asm!("mov cr3, {}", in(reg) self.cr3);
+            for page in ((addr & !0xfff)..=last).step_by(0x1000) {
+                if !self.map_demand_page(page, memory) && write {
+                    self.resolve_copy_on_write(page, memory);
+                }
+            }
+            asm!("mov cr3, {}", in(reg) prev_cr3);
+        }
+    }
+
+    // Moves the end of the heap to `new_brk`, pages given up are freed.
+    // Returns false if it is outside of the heap area.
+    pub fn set_brk(&mut self, new_brk: usize, memory: &mut KernelMemory) -> bool {
+        if !(USER_HEAP_START..=USER_HEAP_START + USER_HEAP_MAX).contains(&new_brk) {
+            return false;
+        }
+        let new_end = (new_brk + 0xfff) & !0xfff;
+        let heap = self
+            .regions
+            .get_mut(RegionKind::Heap)
+            .expect("Process has no heap");
+        let old_end = heap.end;
+        heap.end = new_end;
+        if new_end < old_end {
+            self.free_frames(new_end, old_end, memory, true);
+        }
+        self.brk = new_brk;
+        true
+    }
+
+    // Gives the frames mapped in `[start, end)` back to the frame allocator,
+    // unmapping them too if the address space stays in use
+    fn free_frames(&mut self, start: usize, end: usize, memory: &mut KernelMemory, unmap: bool) {
+        let frame_alloc = &mut memory.frame_alloc;
+        let free = |_, paddr| {
+            // shared frames stay with the other address spaces
+            if frame_alloc.release(paddr) {
+                frame_alloc.deallocate(paddr);
+            }
+        };
+        if unmap {
+            self.pml4.unmap_range(start, end, free);
+        } else {
+            self.pml4.for_each_page(start, end, free);
+        }
+    }
+
+    // Gives the frames of the program, heap and user stack back to the frame
+    // allocator and frees the page tables. `pml4` is dangling afterwards so
+    // this must only be called once the process has exited.
+    pub fn free_address_space(&mut self, memory: &mut KernelMemory) {
+        for (start, end) in USER_OWNED_RANGES {
+            self.free_frames(start, end, memory, false);
+        }
+        unsafe {
+            self.pml4.free();
+        }
+    }
+}
+
+// Registers a process starts at `entry_point` with
+fn initial_frame(entry_point: u64, cr3: usize) -> TrapFrame {
+    TrapFrame {
+        cr3: cr3 as u64,
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        r11: 0,
+        r10: 0,
+        r9: 0,
+        r8: 0,
+        rbp: 0,
+        rdi: 0,
+        rsi: 0,
+        rdx: 0,
+        rcx: 0,
+        rbx: 0,
+        rax: 0,
+        stack_frame: InterruptStackFrame {
+            rip: entry_point,
+            cs: USER_CODE_SEL,
+            eflags: USER_RFLAGS,
+            rsp: USER_STACK_TOP as u64,
+            ss: USER_DATA_SEL,
+        },
+    }
+}
+
+// The stack and an empty heap, both backed on first touch
+fn initial_regions() -> RegionList {
+    let mut regions = RegionList::new();
+    regions.reserve(
+        USER_STACK_TOP - STACK_SIZE,
+        USER_STACK_TOP,
+        RegionKind::Stack,
+    );
+    regions.reserve(USER_HEAP_START, USER_HEAP_START, RegionKind::Heap);
+    regions
+}
+
+// Loads `file_data` as an ELF into a new address space. Returns the entry
+// point, the page table and its physical address, or None if it is not a
+// valid ELF.
+fn load_address_space(
+    file_data: Vec<u8>,
+    memory: &mut KernelMemory,
+) -> Option<(u64, &'static mut PML4, usize)> {
+    let (user_pml4, entry_point) = ElfLoader::load(
+        file_data,
+        &mut memory.frame_alloc,
+        memory.pml4,
+        memory.stack_phys,
+    )?;
+    map_kernel_elf_into_user(&memory.prog_header_entries, user_pml4);
+    let cr3 = virt_to_phys(user_pml4 as *const _ as usize);
+    Some((entry_point, user_pml4, cr3))
+}
+
+// Loads `file_data` as an ELF into a new address space and queues it to run.
+// The user stack is mapped as it gets used. Returns the pid, or None if it is
+// not a valid ELF.
+pub fn create_process(file_data: Vec<u8>) -> Option<usize> {
+    let (entry_point, user_pml4, cr3) = {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("Kernel memory not setup");
+        load_address_space(file_data, memory)?
+    };
+    Some(scheduler::spawn(entry_point, user_pml4, cr3))
+}
+
+// Resolves a page fault from user mode in the current process, see
+// `Process::handle_fault`. Runs on the page table of the process.
+pub fn handle_page_fault(vaddr: usize, present: bool, write: bool) -> bool {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let pid = match kernel_data.current {
+        Some(pid) => pid,
+        None => return false,
+    };
+    let process = match kernel_data.processes[pid].as_mut() {
+        Some(process) => process,
+        None => return false,
+    };
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    match kernel_memory.as_mut() {
+        Some(memory) => process.handle_fault(vaddr, present, write, memory),
+        None => false,
+    }
+}
+
+// Closes the files and frees the memory of the current process, then leaves
+// it as a zombie for its parent to collect. The kernel page table must be loaded.
+pub fn exit_current_process(code: i32) -> ! {
+    {
+        let mut kernel_data = KERNEL_DATA.lock();
+        let kernel_data = &mut *kernel_data;
+        let pid = kernel_data.current.expect("Exit without a current process");
+        let process = kernel_data.processes[pid]
+            .as_mut()
+            .expect("Current process does not exist");
+
+        if let Some(fs) = kernel_data.fs.as_mut() {
+            for fd in 0..MAX_OPEN_FILES {
+                if let Some(file) = process.files.remove(fd) {
+                    let _ = fs.close(file.node);
+                }
+            }
+        }
+        {
+            let mut kernel_memory = KERNEL_MEMORY.lock();
+            let memory = kernel_memory.as_mut().expect("Kernel memory not setup");
+            process.free_address_space(memory);
+        }
+        process.state = ProcessState::Zombie(code);
+        let parent = process.parent;
+
+        for child in kernel_data.processes.iter_mut().flatten() {
+            if child.parent == Some(pid) {
+                child.parent = None;
+            }
+        }
+        if let Some(parent_pid) = parent {
+            if let Some(parent) = kernel_data.processes[parent_pid].as_mut() {
+                if parent.state == ProcessState::Waiting(pid) {
+                    parent.state = ProcessState::Ready;
+                    kernel_data.run_queue.push_back(parent_pid);
+                }
+            }
+        }
+    }
+    scheduler::exit_current();
+}
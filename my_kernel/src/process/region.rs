@@ -0,0 +1,62 @@
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Stack,
+    Heap,
+}
+
+// A range of user memory the process may touch. Frames are only given to
+// pages in it once they fault, see `Process::map_demand_page`.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub start: usize,
+    // exclusive and page aligned
+    pub end: usize,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        self.start <= vaddr && vaddr < self.end
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionList {
+    regions: Vec<Region>,
+}
+
+impl Default for RegionList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegionList {
+    pub fn new() -> Self {
+        RegionList {
+            regions: Vec::new(),
+        }
+    }
+
+    // Returns false if it overlaps a region that is already reserved
+    pub fn reserve(&mut self, start: usize, end: usize, kind: RegionKind) -> bool {
+        if !start.is_multiple_of(0x1000) || !end.is_multiple_of(0x1000) || start > end {
+            return false;
+        }
+        if self.regions.iter().any(|r| start < r.end && r.start < end) {
+            return false;
+        }
+        self.regions.push(Region { start, end, kind });
+        true
+    }
+
+    pub fn find(&self, vaddr: usize) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(vaddr))
+    }
+
+    pub fn get_mut(&mut self, kind: RegionKind) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|r| r.kind == kind)
+    }
+}
@@ -0,0 +1,180 @@
+use core::arch::{asm, global_asm};
+
+use crate::kernel_data::{KernelData, KERNEL_DATA};
+use crate::memory::page_table::PML4;
+use crate::process::{Process, ProcessState};
+use crate::user_mode::set_syscall_stack;
+
+extern "C" {
+    fn switch_context(prev_rsp: *mut usize, next_rsp: usize);
+}
+
+// Saves the callee saved registers on the current stack, stores the stack
+// pointer in `prev_rsp` and resumes whatever was saved on `next_rsp`.
+// Arguments are passed in order of:
+// RDI, RSI
+global_asm!(
+    ".global switch_context
+    switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret"
+);
+
+// Returns the pid the process was given
+pub fn spawn(entry_point: u64, pml4: &'static mut PML4, cr3: usize) -> usize {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let parent = kernel_data.current;
+    insert_process(&mut kernel_data, |pid| {
+        Process::new(pid, parent, entry_point, pml4, cr3)
+    })
+    .expect("Too many processes")
+}
+
+// Puts the process `make` builds for the lowest free pid in the process table
+// and queues it. Returns None if the table is full.
+pub fn insert_process(
+    kernel_data: &mut KernelData,
+    make: impl FnOnce(usize) -> Process,
+) -> Option<usize> {
+    let pid = kernel_data.processes.iter().position(|p| p.is_none())?;
+    kernel_data.processes[pid] = Some(make(pid));
+    kernel_data.run_queue.push_back(pid);
+    Some(pid)
+}
+
+// Marks `pid` as running and points the tss and syscall stack at its kernel stack.
+// Returns the stack pointer to switch to.
+fn make_current(kernel_data: &mut KernelData, pid: usize) -> usize {
+    reap_orphans(kernel_data);
+    let process = kernel_data.processes[pid]
+        .as_mut()
+        .expect("Scheduled process does not exist");
+    process.state = ProcessState::Running;
+    let stack_top = process.kernel_stack_top();
+    let next_rsp = process.saved_rsp;
+
+    kernel_data
+        .tss
+        .as_mut()
+        .expect("TSS not setup")
+        .set_rsp0(stack_top as u64);
+    set_syscall_stack(stack_top);
+    kernel_data.current = Some(pid);
+    next_rsp
+}
+
+// Drops zombies nobody will wait for. The current process may still be
+// running on its kernel stack so it is left for the next switch.
+fn reap_orphans(kernel_data: &mut KernelData) {
+    let current = kernel_data.current;
+    for (pid, slot) in kernel_data.processes.iter_mut().enumerate() {
+        let orphaned_zombie = slot
+            .as_ref()
+            .is_some_and(|p| p.parent.is_none() && matches!(p.state, ProcessState::Zombie(_)));
+        if orphaned_zombie && current != Some(pid) {
+            *slot = None;
+        }
+    }
+}
+
+// Round robin, the current process goes to the back of the run queue
+// and the one at the front gets the cpu.
+// Must be called with interrupts disabled.
+pub fn schedule() {
+    let (prev_rsp, next_rsp) = {
+        let mut kernel_data = KERNEL_DATA.lock();
+        let next = match kernel_data.run_queue.pop_front() {
+            Some(next) => next,
+            None => return, // nothing else to run, keep going
+        };
+        let prev = kernel_data.current.expect("schedule called before start");
+
+        let prev_process = kernel_data.processes[prev]
+            .as_mut()
+            .expect("Current process does not exist");
+        prev_process.state = ProcessState::Ready;
+        let prev_rsp = &mut prev_process.saved_rsp as *mut usize;
+        kernel_data.run_queue.push_back(prev);
+
+        (prev_rsp, make_current(&mut kernel_data, next))
+    };
+    // the lock must be released before switching, the next process
+    // may not come back through here
+    unsafe {
+        switch_context(prev_rsp, next_rsp);
+    }
+}
+
+// Switches away from the current process without putting it back on the run
+// queue, something else has to make it ready again. Falls back to the idle
+// loop if nothing can run.
+// Must be called with interrupts disabled and without holding `KERNEL_DATA`.
+pub fn block_current() {
+    let (prev_rsp, next_rsp) = {
+        let mut kernel_data = KERNEL_DATA.lock();
+        let prev = kernel_data
+            .current
+            .expect("block_current called before start");
+        let prev_rsp = &mut kernel_data.processes[prev]
+            .as_mut()
+            .expect("Current process does not exist")
+            .saved_rsp as *mut usize;
+        let next_rsp = match kernel_data.run_queue.pop_front() {
+            Some(next) => make_current(&mut kernel_data, next),
+            None => {
+                kernel_data.current = None;
+                kernel_data.idle_rsp
+            }
+        };
+        (prev_rsp, next_rsp)
+    };
+    unsafe {
+        switch_context(prev_rsp, next_rsp);
+    }
+}
+
+// Leaves the current process for good, its state must already be a zombie
+pub fn exit_current() -> ! {
+    block_current();
+    panic!("Exited process was scheduled again");
+}
+
+// Runs the processes in the run queue from the boot stack. Whenever nothing
+// is runnable we come back here and wait for an interrupt.
+pub fn start() -> ! {
+    loop {
+        let next_rsp = {
+            let mut kernel_data = KERNEL_DATA.lock();
+            match kernel_data.run_queue.pop_front() {
+                Some(next) => {
+                    let next_rsp = make_current(&mut kernel_data, next);
+                    let idle_rsp = &mut kernel_data.idle_rsp as *mut usize;
+                    Some((idle_rsp, next_rsp))
+                }
+                None => None,
+            }
+        };
+        match next_rsp {
+            Some((idle_rsp, next_rsp)) => unsafe {
+                switch_context(idle_rsp, next_rsp);
+            },
+            // interrupts only get enabled while halted, the kernel is not preemptible
+            None => unsafe {
+                asm!("sti", "hlt", "cli");
+            },
+        }
+    }
+}
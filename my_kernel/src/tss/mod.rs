@@ -1,7 +1,14 @@
 use crate::memory::stack::KERN_STACK_TOP;
 use alloc::boxed::Box;
+use alloc::vec;
 use core::mem;
 
+// Interrupt stack the double fault handler runs on, so a fault that leaves the
+// kernel stack unusable (like overflowing it) can still be reported.
+// This is the IST entry number, ist1 here.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024;
+
 #[repr(C, packed)]
 pub struct TSS {
     res0: u32,
@@ -32,7 +39,7 @@ impl TSS {
             rsp2: 0,
             res1: 0,
             res2: 0,
-            ist1: 0,
+            ist1: new_interrupt_stack(DOUBLE_FAULT_STACK_SIZE),
             ist2: 0,
             ist3: 0,
             ist4: 0,
@@ -46,6 +53,11 @@ impl TSS {
         }
     }
 
+    // stack the cpu switches to when an interrupt arrives from ring 3
+    pub fn set_rsp0(&mut self, rsp: u64) {
+        self.rsp0 = rsp;
+    }
+
     pub fn create_tss_on_heap() -> Box<TSS> {
         Box::new(TSS::new())
     }
@@ -71,3 +83,10 @@ impl TSS {
         (final_value_hi, final_value_lo)
     }
 }
+
+// Top of a new stack on the heap, which every address space maps. It is
+// never freed.
+fn new_interrupt_stack(size: usize) -> u64 {
+    let stack = Box::leak(vec![0u8; size].into_boxed_slice());
+    ((stack.as_ptr() as usize + size) & !0xf) as u64
+}
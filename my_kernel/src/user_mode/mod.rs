@@ -1,49 +1,25 @@
-use alloc::vec::Vec;
+use crate::acpi::power::{reboot, shutdown};
+use crate::cpu::{read_cr3, write_msr};
+use crate::kernel_data::{KernelData, KERNEL_DATA, KERNEL_MEMORY};
 
-use crate::apic::{
-    disable_pic, enable_apic, get_apic_base, set_apic_base, set_apic_tpr, start_apic_timer,
-};
-use crate::cpu::write_msr;
+use crate::fs::vfs::{FileSystem, OpenFile, OpenFlags, Whence};
+use crate::fs::{read_file, FsError, MAX_FILE_SIZE};
 use crate::gdt::{USER_CODE_SEL, USER_DATA_SEL};
-use crate::interrupts::enable_hardware_interrupts;
-use crate::memory::heap::translate_usize_to_phys;
-
-use crate::memory::page_table::{current_page_table, PhysPage4KiB, PML4};
-use crate::memory::stack::{KERN_STACK_TOP, USER_STACK_TOP};
-use crate::println;
+use crate::process::scheduler::{block_current, insert_process};
+use crate::process::{create_process, exit_current_process, Process, ProcessState};
+use crate::vga_buffer::WRITER;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::arch::{asm, global_asm};
+use syscall_defs::{Syscall, SyscallError, SyscallResult};
 
-pub fn enter_user_mode(
-    entry_point: u64,
-    user_pml4: &mut PML4,
-    heap_regions: &Vec<(&'static PhysPage4KiB, usize)>,
-) -> ! {
-    unsafe {
-        // save kernel cr3
-        kern_cr3 = current_page_table() as *const _ as usize;
-        syscall_stack = KERN_STACK_TOP;
-        let cr3 = user_pml4 as *const _ as usize;
-        let cr3 = translate_usize_to_phys(heap_regions, cr3);
-        let user_data_sel: u64 = USER_DATA_SEL;
-        let user_code_sel: u64 = USER_CODE_SEL;
-        let user_stack: usize = USER_STACK_TOP;
-
-        asm!(
-            "mov cr3, {:r}",
-            "push {:r}",
-            "push {:r}",
-            "pushfq",
-            "push {:r}",
-            "push {:r}",
-            "iretq",
-            in(reg) cr3,
-            in(reg) user_data_sel,
-            in(reg) user_stack,
-            in(reg) user_code_sel,
-            in(reg) entry_point);
-    }
-    loop {}
-}
+// longest path CreateProc and Open accept
+const MAX_PATH_LEN: usize = 0x100;
+
+// Print, Read and Write move at most this much per call
+const MAX_IO_LEN: usize = 0x10000;
 
 pub fn enable_syscalls() {
     let addr_to_exec: usize = syscall_test as *const () as usize;
@@ -56,6 +32,26 @@ pub fn enable_syscalls() {
 
         // set syscall handler
         write_msr(0xC0000082, addr_to_exec as u64);
+
+        // mask interrupts while in the syscall handler
+        write_msr(0xC0000084, 0x200);
+
+        // save kernel cr3, syscalls and interrupts switch to it on entry
+        kern_cr3 = read_cr3();
+    }
+}
+
+// Exceptions from user mode arrive with the page table of the process still loaded
+pub fn load_kernel_page_table() {
+    unsafe {
+        asm!("mov cr3, {}", in(reg) kern_cr3);
+    }
+}
+
+// The stack syscalls run on, this is the kernel stack of the current process
+pub fn set_syscall_stack(stack_top: usize) {
+    unsafe {
+        syscall_stack = stack_top;
     }
 }
 
@@ -65,93 +61,407 @@ extern "C" {
     static mut syscall_stack: usize;
 }
 
+// The entry saves the user registers as a `TrapFrame` at the top of the kernel
+// stack, see `Process::syscall_frame`, and restores them on the way out except
+// for rax which holds the result.
 global_asm!(
     ".data
 
-    .global
+    .global rsp_storage
     rsp_storage:
     .quad  0xffeeddccbbaa9988
 
-    .global
+    .global kern_cr3
     kern_cr3:
     .quad  0xffeeddccbbaa9988
 
-    .global
-    user_cr3:
-    .quad  0xffeeddccbbaa9988
-
-    .global
+    .global syscall_stack
     syscall_stack:
     .quad  0xFFFFF00000000000
 
     .text
 
-    .global
+    .global syscall_test
     syscall_test:
     mov rsp_storage[rip], rsp
     mov rsp, syscall_stack[rip]
+    push {user_ss}
+    push qword ptr rsp_storage[rip]
+    push r11
+    push {user_cs}
     push rcx
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
     push r11
+    push r12
+    push r13
+    push r14
+    push r15
     mov rcx, 0x10
     mov ds, rcx
     mov es, rcx
     mov fs, rcx
     mov gs, rcx
     mov rcx, cr3
-    mov user_cr3[rip], rcx
+    push rcx
     mov rcx, kern_cr3[rip]
     mov cr3, rcx
     mov rcx, r10
     mov r9, rax
+    mov rbx, rsp
+    and rsp, -16
     call syscall_handler
-    mov rcx, user_cr3[rip]
+    mov rsp, rbx
+    pop rcx
     mov cr3, rcx
+    pop r15
+    pop r14
+    pop r13
+    pop r12
     pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
     pop rcx
-    mov rsp, rsp_storage[rip]
-    sysretq"
+    pop rbx
+    add rsp, 8
+    pop rcx
+    add rsp, 8
+    pop r11
+    pop rsp
+    sysretq",
+    user_ss = const USER_DATA_SEL,
+    user_cs = const USER_CODE_SEL,
 );
 
-#[repr(u64)]
-#[derive(Debug)]
-enum Syscall {
-    Print = 0,
-    CreateProc = 1,
-    EnableTimer = 2,
+impl From<FsError> for SyscallError {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::Io | FsError::Corrupt => SyscallError::Io,
+            FsError::NotFound => SyscallError::NotFound,
+            FsError::AlreadyExists => SyscallError::AlreadyExists,
+            FsError::InvalidName | FsError::InvalidPath => SyscallError::InvalidArgument,
+            FsError::NoSpace | FsError::NoInodes => SyscallError::NoSpace,
+            FsError::FileTooLarge => SyscallError::FileTooLarge,
+            FsError::NotAFile => SyscallError::NotAFile,
+            FsError::NotADirectory => SyscallError::NotADirectory,
+            FsError::DirectoryNotEmpty => SyscallError::DirectoryNotEmpty,
+        }
+    }
+}
+
+// Copies `len` bytes at `addr` out of `process`
+// Returns None if any of it is outside the process or not mapped for user mode
+fn copy_from_process(process: &mut Process, addr: usize, len: usize) -> Option<Vec<u8>> {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut()?;
+
+    if !process.owns_range(addr, len) {
+        return None;
+    }
+    process.fault_in_range(addr, len, false, memory);
+    if !process.pml4.user_range_accessible(addr, len, false) {
+        return None;
+    }
+
+    // the heap is mapped in every process so we can copy straight into it
+    let mut buf = vec![0u8; len];
+    unsafe {
+        asm!("mov cr3, {}", in(reg) process.cr3);
+        core::ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), len);
+        asm!("mov cr3, {}", in(reg) kern_cr3);
+    }
+    Some(buf)
+}
+
+// Copies `data` to `addr` in `process`
+// Returns false without writing anything if it is outside the process or not
+// writable from user mode
+fn copy_to_process(process: &mut Process, addr: usize, data: &[u8]) -> bool {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = match kernel_memory.as_mut() {
+        Some(memory) => memory,
+        None => return false,
+    };
+
+    if !process.owns_range(addr, data.len()) {
+        return false;
+    }
+    process.fault_in_range(addr, data.len(), true, memory);
+    if !process.pml4.user_range_accessible(addr, data.len(), true) {
+        return false;
+    }
+
+    unsafe {
+        asm!("mov cr3, {}", in(reg) process.cr3);
+        core::ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, data.len());
+        asm!("mov cr3, {}", in(reg) kern_cr3);
+    }
+    true
+}
+
+// Copies `len` bytes at `addr` out of the current process
+// Returns None if any of it is not mapped for user mode
+pub fn copy_from_user(addr: usize, len: usize) -> Option<Vec<u8>> {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let pid = kernel_data.current?;
+    let process = kernel_data.processes[pid].as_mut()?;
+    copy_from_process(process, addr, len)
+}
+
+// Copies a path argument out of the current process
+fn path_from_user(ptr: u64, len: u64) -> Result<String, SyscallError> {
+    let len = len as usize;
+    if len > MAX_PATH_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let path = copy_from_user(ptr as usize, len).ok_or(SyscallError::BadAddress)?;
+    String::from_utf8(path).map_err(|_| SyscallError::InvalidArgument)
+}
+
+// The running process and the root filesystem, borrowed together
+fn current_and_fs(
+    kernel_data: &mut KernelData,
+) -> Result<(&mut Process, &mut Box<dyn FileSystem>), SyscallError> {
+    let pid = kernel_data.current.ok_or(SyscallError::Unknown)?;
+    Ok((
+        kernel_data.processes[pid]
+            .as_mut()
+            .ok_or(SyscallError::Unknown)?,
+        kernel_data.fs.as_mut().ok_or(SyscallError::Io)?,
+    ))
+}
+
+fn sys_open(path_ptr: u64, path_len: u64, flags: u64) -> SyscallResult {
+    let path = path_from_user(path_ptr, path_len)?;
+    let flags = OpenFlags::from_bits(flags).ok_or(SyscallError::InvalidArgument)?;
+
+    let mut kernel_data = KERNEL_DATA.lock();
+    let (process, fs) = current_and_fs(&mut kernel_data)?;
+    let node = fs.open(&path, flags)?;
+    let file = OpenFile {
+        node,
+        offset: 0,
+        flags,
+    };
+    match process.files.insert(file) {
+        Some(fd) => Ok(fd as u64),
+        None => {
+            let _ = fs.close(node);
+            Err(SyscallError::TooManyOpenFiles)
+        }
+    }
+}
+
+fn sys_read(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let (process, fs) = current_and_fs(&mut kernel_data)?;
+    let (node, offset) = match process.files.get_mut(fd as usize) {
+        Some(file) if file.flags.contains(OpenFlags::READ) => (file.node, file.offset),
+        _ => return Err(SyscallError::BadFileDescriptor),
+    };
+
+    let data = fs.read(node, offset, (len as usize).min(MAX_IO_LEN))?;
+    if !copy_to_process(process, buf as usize, &data) {
+        return Err(SyscallError::BadAddress);
+    }
+    if let Some(file) = process.files.get_mut(fd as usize) {
+        file.offset += data.len() as u64;
+    }
+    Ok(data.len() as u64)
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let (process, fs) = current_and_fs(&mut kernel_data)?;
+    let data = copy_from_process(process, buf as usize, (len as usize).min(MAX_IO_LEN))
+        .ok_or(SyscallError::BadAddress)?;
+    let file = match process.files.get_mut(fd as usize) {
+        Some(file) if file.flags.contains(OpenFlags::WRITE) => file,
+        _ => return Err(SyscallError::BadFileDescriptor),
+    };
+
+    if file.flags.contains(OpenFlags::APPEND) {
+        file.offset = fs.stat(file.node)?.size;
+    }
+    let written = fs.write(file.node, file.offset, &data)?;
+    file.offset += written as u64;
+    Ok(written as u64)
+}
+
+fn sys_close(fd: u64) -> SyscallResult {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let (process, fs) = current_and_fs(&mut kernel_data)?;
+    let file = process
+        .files
+        .remove(fd as usize)
+        .ok_or(SyscallError::BadFileDescriptor)?;
+    fs.close(file.node)?;
+    Ok(0)
+}
+
+// Returns the new offset, it may point past the end of the file
+fn sys_lseek(fd: u64, offset: u64, whence: u64) -> SyscallResult {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let (process, fs) = current_and_fs(&mut kernel_data)?;
+    let file = process
+        .files
+        .get_mut(fd as usize)
+        .ok_or(SyscallError::BadFileDescriptor)?;
+
+    let base = match Whence::from_u64(whence).ok_or(SyscallError::InvalidArgument)? {
+        Whence::Set => 0,
+        Whence::Current => file.offset,
+        Whence::End => fs.stat(file.node)?.size,
+    };
+    // `offset` is signed for the user
+    match base.checked_add_signed(offset as i64) {
+        Some(new_offset) if new_offset <= MAX_FILE_SIZE => {
+            file.offset = new_offset;
+            Ok(new_offset)
+        }
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+// Writes `len` bytes at `buf` to the console, returns how many were written
+fn sys_print(buf: u64, len: u64) -> SyscallResult {
+    let len = (len as usize).min(MAX_IO_LEN);
+    let data = copy_from_user(buf as usize, len).ok_or(SyscallError::BadAddress)?;
+    WRITER.lock().write_bytes(&data);
+    Ok(len as u64)
+}
+
+fn sys_create_proc(path_ptr: u64, path_len: u64) -> SyscallResult {
+    let path = path_from_user(path_ptr, path_len)?;
+    let file_data = read_file(&path)?;
+    let pid = create_process(file_data).ok_or(SyscallError::InvalidExecutable)?;
+    Ok(pid as u64)
+}
+
+fn sys_exit(code: u64) -> ! {
+    exit_current_process(code as i32)
+}
+
+// Blocks until the child `pid` exits and returns its exit code
+fn sys_wait(pid: u64) -> SyscallResult {
+    let pid = pid as usize;
+    loop {
+        {
+            let mut kernel_data = KERNEL_DATA.lock();
+            let current = kernel_data.current.ok_or(SyscallError::Unknown)?;
+            let child = kernel_data
+                .processes
+                .get(pid)
+                .and_then(|p| p.as_ref())
+                .filter(|p| p.parent == Some(current))
+                .ok_or(SyscallError::NoSuchProcess)?;
+
+            if let ProcessState::Zombie(code) = child.state {
+                // this drops the kernel stack of the child as well
+                kernel_data.processes[pid] = None;
+                return Ok(code as u32 as u64);
+            }
+            kernel_data.processes[current]
+                .as_mut()
+                .ok_or(SyscallError::Unknown)?
+                .state = ProcessState::Waiting(pid);
+        }
+        block_current();
+    }
+}
+
+// Moves the end of the heap to `addr` and returns the new end, 0 only queries it
+fn sys_brk(addr: u64) -> SyscallResult {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let pid = kernel_data.current.ok_or(SyscallError::Unknown)?;
+    let process = kernel_data.processes[pid]
+        .as_mut()
+        .ok_or(SyscallError::Unknown)?;
+    if addr != 0 {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().ok_or(SyscallError::Unknown)?;
+        if !process.set_brk(addr as usize, memory) {
+            return Err(SyscallError::NoSpace);
+        }
+    }
+    Ok(process.brk as u64)
+}
+
+// Returns the pid of the child to the parent and 0 to the child
+fn sys_fork() -> SyscallResult {
+    let mut kernel_data = KERNEL_DATA.lock();
+    let parent_pid = kernel_data.current.ok_or(SyscallError::Unknown)?;
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut().ok_or(SyscallError::Unknown)?;
+
+    let mut parent = kernel_data.processes[parent_pid]
+        .take()
+        .ok_or(SyscallError::Unknown)?;
+    let child_pid = insert_process(&mut kernel_data, |pid| parent.fork(pid, memory));
+    kernel_data.processes[parent_pid] = Some(parent);
+    child_pid
+        .map(|pid| pid as u64)
+        .ok_or(SyscallError::TooManyProcesses)
+}
+
+// Only returns if the program couldn't be started
+fn sys_exec(path_ptr: u64, path_len: u64) -> SyscallResult {
+    let path = path_from_user(path_ptr, path_len)?;
+    let file_data = read_file(&path)?;
+
+    let mut kernel_data = KERNEL_DATA.lock();
+    let pid = kernel_data.current.ok_or(SyscallError::Unknown)?;
+    let process = kernel_data.processes[pid]
+        .as_mut()
+        .ok_or(SyscallError::Unknown)?;
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut().ok_or(SyscallError::Unknown)?;
+    if !process.exec(file_data, memory) {
+        return Err(SyscallError::InvalidExecutable);
+    }
+    Ok(0)
 }
 
 #[no_mangle]
 extern "sysv64" fn syscall_handler(
-    _arg0: u64,
-    _arg1: u64,
-    _arg2: u64,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
     _arg3: u64,
     _arg4: u64,
-    syscall: Syscall,
+    syscall: u64,
 ) -> u64 {
-    match syscall {
-        Syscall::Print => println!("Syscall num: {:#?}", syscall),
-        Syscall::CreateProc => println!("Syscall num: {:#?}", syscall),
-        Syscall::EnableTimer => {
-            println!("Enabling timer");
-            let apic_base = get_apic_base();
-            unsafe {
-                set_apic_base(apic_base);
-                enable_apic(apic_base);
-                set_apic_tpr(apic_base, 0);
-            }
-            disable_pic();
-            println!("Enabling hwi");
-            enable_hardware_interrupts();
-            println!("starting timer");
-            unsafe {
-                start_apic_timer(apic_base);
-            }
-        }
-    }
-
-    let ret: u64 = 0x11223344AABBCCDD;
-    ret
+    let result = match Syscall::from_u64(syscall) {
+        Some(Syscall::Print) => sys_print(arg0, arg1),
+        Some(Syscall::CreateProc) => sys_create_proc(arg0, arg1),
+        Some(Syscall::Open) => sys_open(arg0, arg1, arg2),
+        Some(Syscall::Read) => sys_read(arg0, arg1, arg2),
+        Some(Syscall::Write) => sys_write(arg0, arg1, arg2),
+        Some(Syscall::Close) => sys_close(arg0),
+        Some(Syscall::Lseek) => sys_lseek(arg0, arg1, arg2),
+        Some(Syscall::Exit) => sys_exit(arg0),
+        Some(Syscall::Wait) => sys_wait(arg0),
+        Some(Syscall::Brk) => sys_brk(arg0),
+        Some(Syscall::Fork) => sys_fork(),
+        Some(Syscall::Exec) => sys_exec(arg0, arg1),
+        Some(Syscall::Shutdown) => shutdown(),
+        Some(Syscall::Reboot) => reboot(),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    syscall_defs::encode(result)
 }
 
 // fn test_syscall(syscall: u64) -> u64 {
@@ -0,0 +1,134 @@
+#![no_std]
+
+// Definitions shared by the kernel and user programs
+//
+// The syscall number goes in rax and arguments in rdi, rsi, rdx, r10, r8.
+// The result comes back in rax, values in the top `MAX_ERROR` of the range
+// are errors, see `encode` and `decode`.
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    Print = 0,
+    CreateProc = 1,
+    // 2 was EnableTimer, the kernel starts the timer itself now
+    Open = 3,
+    Read = 4,
+    Write = 5,
+    Close = 6,
+    Lseek = 7,
+    Exit = 8,
+    Wait = 9,
+    Brk = 10,
+    Fork = 11,
+    Exec = 12,
+    Shutdown = 13,
+    Reboot = 14,
+}
+
+impl Syscall {
+    pub fn from_u64(num: u64) -> Option<Self> {
+        Some(match num {
+            0 => Syscall::Print,
+            1 => Syscall::CreateProc,
+            3 => Syscall::Open,
+            4 => Syscall::Read,
+            5 => Syscall::Write,
+            6 => Syscall::Close,
+            7 => Syscall::Lseek,
+            8 => Syscall::Exit,
+            9 => Syscall::Wait,
+            10 => Syscall::Brk,
+            11 => Syscall::Fork,
+            12 => Syscall::Exec,
+            13 => Syscall::Shutdown,
+            14 => Syscall::Reboot,
+            _ => return None,
+        })
+    }
+}
+
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    Unknown = 1,
+    NoSuchSyscall = 2,
+    // a pointer argument is not mapped for user mode
+    BadAddress = 3,
+    InvalidArgument = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    NoSpace = 7,
+    Io = 8,
+    BadFileDescriptor = 9,
+    TooManyOpenFiles = 10,
+    NotAFile = 11,
+    NotADirectory = 12,
+    DirectoryNotEmpty = 13,
+    FileTooLarge = 14,
+    InvalidExecutable = 15,
+    // not a child of the caller
+    NoSuchProcess = 16,
+    TooManyProcesses = 17,
+}
+
+impl SyscallError {
+    pub fn from_u64(code: u64) -> Self {
+        match code {
+            2 => SyscallError::NoSuchSyscall,
+            3 => SyscallError::BadAddress,
+            4 => SyscallError::InvalidArgument,
+            5 => SyscallError::NotFound,
+            6 => SyscallError::AlreadyExists,
+            7 => SyscallError::NoSpace,
+            8 => SyscallError::Io,
+            9 => SyscallError::BadFileDescriptor,
+            10 => SyscallError::TooManyOpenFiles,
+            11 => SyscallError::NotAFile,
+            12 => SyscallError::NotADirectory,
+            13 => SyscallError::DirectoryNotEmpty,
+            14 => SyscallError::FileTooLarge,
+            15 => SyscallError::InvalidExecutable,
+            16 => SyscallError::NoSuchProcess,
+            17 => SyscallError::TooManyProcesses,
+            _ => SyscallError::Unknown,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+// Exit code `wait` reports for a process the kernel killed after an exception
+pub const EXIT_KILLED: i32 = -1;
+
+// Results above `u64::MAX - MAX_ERROR` are errors
+pub const MAX_ERROR: u64 = 0xfff;
+
+pub fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(e) => (e as u64).wrapping_neg(),
+    }
+}
+
+pub fn decode(ret: u64) -> SyscallResult {
+    if ret > u64::MAX - MAX_ERROR {
+        Err(SyscallError::from_u64(ret.wrapping_neg()))
+    } else {
+        Ok(ret)
+    }
+}
+
+// flags for Open, combine with |
+pub const O_READ: u64 = 1 << 0;
+pub const O_WRITE: u64 = 1 << 1;
+// create the file if it doesn't exist
+pub const O_CREATE: u64 = 1 << 2;
+pub const O_TRUNCATE: u64 = 1 << 3;
+// every write goes to the end of the file
+pub const O_APPEND: u64 = 1 << 4;
+
+// whence values for Lseek
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
@@ -3,12 +3,22 @@
 
 use core::panic::PanicInfo;
 
+use user_lib::println;
+use user_lib::syscalls::exit;
+
+const N: u64 = 50;
+
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
-    loop {}
+pub extern "sysv64" fn _start() -> ! {
+    let (mut a, mut b) = (0u64, 1u64);
+    for _ in 0..N {
+        (a, b) = (b, a + b);
+    }
+    println!("fib: fib({}) = {}", N, a);
+    exit(0)
 }
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+    exit(-1)
 }
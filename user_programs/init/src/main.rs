@@ -3,16 +3,29 @@
 
 use core::panic::PanicInfo;
 
-use user_lib::syscalls::{create_proc, enable_timer, print};
+use user_lib::println;
+use user_lib::syscalls::{exec, exit, fork, shutdown, wait};
 
 #[no_mangle]
 pub extern "sysv64" fn _start() -> ! {
-    print();
-    create_proc();
-    print();
-    create_proc();
-    enable_timer();
-    loop {}
+    println!("init: started");
+    match fork() {
+        Ok(0) => {
+            let e = exec("fib");
+            println!("init: couldn't exec fib: {:?}", e);
+            exit(1)
+        }
+        Ok(pid) => {
+            println!("init: forked fib with pid {}", pid);
+            match wait(pid) {
+                Ok(code) => println!("init: fib exited with {}", code),
+                Err(e) => println!("init: couldn't wait for fib: {:?}", e),
+            }
+        }
+        Err(e) => println!("init: couldn't fork: {:?}", e),
+    }
+    // nothing left to do once fib is done
+    shutdown()
 }
 
 #[panic_handler]
@@ -1,3 +1,4 @@
 #![no_std]
 
+pub mod console;
 pub mod syscalls;
@@ -1,34 +1,218 @@
 use core::arch::asm;
 
+use syscall_defs::{decode, SEEK_CUR, SEEK_END, SEEK_SET};
+pub use syscall_defs::{
+    Syscall, SyscallError, SyscallResult, O_APPEND, O_CREATE, O_READ, O_TRUNCATE, O_WRITE,
+};
+
 #[repr(u64)]
-enum Syscall {
-    Print = 0,
-    CreateProc = 1,
-    EnableTimer = 2,
+pub enum Whence {
+    Set = SEEK_SET,
+    Current = SEEK_CUR,
+    End = SEEK_END,
+}
+
+// The kernel takes the syscall number in rax and arguments in
+// rdi, rsi, rdx, r10, r8. It returns in rax and does not preserve
+// the other caller saved registers.
+pub unsafe fn syscall_0(syscall: Syscall) -> SyscallResult {
+    let mut ret: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") syscall as u64 => ret,
+            clobber_abi("C"),
+        );
+    }
+    decode(ret)
+}
+
+pub unsafe fn syscall_1(syscall: Syscall, arg0: u64) -> SyscallResult {
+    let mut ret: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") syscall as u64 => ret,
+            in("rdi") arg0,
+            clobber_abi("C"),
+        );
+    }
+    decode(ret)
+}
+
+pub unsafe fn syscall_2(syscall: Syscall, arg0: u64, arg1: u64) -> SyscallResult {
+    let mut ret: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") syscall as u64 => ret,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            clobber_abi("C"),
+        );
+    }
+    decode(ret)
+}
+
+pub unsafe fn syscall_3(syscall: Syscall, arg0: u64, arg1: u64, arg2: u64) -> SyscallResult {
+    let mut ret: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") syscall as u64 => ret,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            clobber_abi("C"),
+        );
+    }
+    decode(ret)
+}
+
+pub unsafe fn syscall_4(
+    syscall: Syscall,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+) -> SyscallResult {
+    let mut ret: u64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") syscall as u64 => ret,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            in("r10") arg3,
+            clobber_abi("C"),
+        );
+    }
+    decode(ret)
 }
 
-unsafe extern "C" fn syscall_0(syscall: Syscall) -> u64 {
+pub unsafe fn syscall_5(
+    syscall: Syscall,
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+) -> SyscallResult {
     let mut ret: u64;
     unsafe {
         asm!(
-            "mov rax, {:r}",
             "syscall",
-            "mov {:r}, rax",
-            in(reg) syscall as u64,
-            out(reg) ret
+            inlateout("rax") syscall as u64 => ret,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            in("r10") arg3,
+            in("r8") arg4,
+            clobber_abi("C"),
         );
     }
-    ret
+    decode(ret)
+}
+
+// Writes `s` to the console, returns the number of bytes written
+pub fn print(s: &str) -> Result<usize, SyscallError> {
+    unsafe { syscall_2(Syscall::Print, s.as_ptr() as u64, s.len() as u64).map(|n| n as usize) }
+}
+
+// Starts the program at `path`, returns its pid
+pub fn create_proc(path: &str) -> Result<u64, SyscallError> {
+    unsafe { syscall_2(Syscall::CreateProc, path.as_ptr() as u64, path.len() as u64) }
+}
+
+// Opens `path` with the O_* `flags`, returns a file descriptor
+pub fn open(path: &str, flags: u64) -> Result<u64, SyscallError> {
+    unsafe {
+        syscall_3(
+            Syscall::Open,
+            path.as_ptr() as u64,
+            path.len() as u64,
+            flags,
+        )
+    }
 }
 
-pub fn print() -> u64 {
-    unsafe { syscall_0(Syscall::Print) }
+// Returns the number of bytes read, 0 at the end of the file
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+    unsafe {
+        syscall_3(Syscall::Read, fd, buf.as_mut_ptr() as u64, buf.len() as u64).map(|n| n as usize)
+    }
+}
+
+// Returns the number of bytes written
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, SyscallError> {
+    unsafe {
+        syscall_3(Syscall::Write, fd, buf.as_ptr() as u64, buf.len() as u64).map(|n| n as usize)
+    }
+}
+
+pub fn close(fd: u64) -> Result<(), SyscallError> {
+    unsafe { syscall_1(Syscall::Close, fd).map(|_| ()) }
 }
 
-pub fn create_proc() -> u64 {
-    unsafe { syscall_0(Syscall::CreateProc) }
+// Returns the new offset
+pub fn lseek(fd: u64, offset: i64, whence: Whence) -> Result<u64, SyscallError> {
+    unsafe { syscall_3(Syscall::Lseek, fd, offset as u64, whence as u64) }
 }
 
-pub fn enable_timer() -> u64 {
-    unsafe { syscall_0(Syscall::EnableTimer) }
+// Ends the calling process, `code` is handed to the parent by `wait`
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        let _ = syscall_1(Syscall::Exit, code as u32 as u64);
+    }
+    unreachable!("exit returned")
+}
+
+// Blocks until the child `pid` exits, returns its exit code
+pub fn wait(pid: u64) -> Result<i32, SyscallError> {
+    unsafe { syscall_1(Syscall::Wait, pid).map(|code| code as i32) }
+}
+
+// Moves the end of the heap to `addr`, returns the new end.
+// Pass 0 to get the current end.
+pub fn brk(addr: usize) -> Result<usize, SyscallError> {
+    unsafe { syscall_1(Syscall::Brk, addr as u64).map(|end| end as usize) }
+}
+
+// Grows the heap by `increment` bytes, returns the start of the new memory
+pub fn sbrk(increment: usize) -> Result<usize, SyscallError> {
+    let old = brk(0)?;
+    let new = old.checked_add(increment).ok_or(SyscallError::NoSpace)?;
+    brk(new)?;
+    Ok(old)
+}
+
+// Duplicates the calling process, returns the pid of the child in the parent
+// and 0 in the child
+pub fn fork() -> Result<u64, SyscallError> {
+    unsafe { syscall_0(Syscall::Fork) }
+}
+
+// Replaces the calling program with the one at `path`, only returns on failure
+pub fn exec(path: &str) -> SyscallError {
+    match unsafe { syscall_2(Syscall::Exec, path.as_ptr() as u64, path.len() as u64) } {
+        Ok(_) => unreachable!("exec returned"),
+        Err(e) => e,
+    }
+}
+
+// Powers the machine off, every process goes with it
+pub fn shutdown() -> ! {
+    unsafe {
+        let _ = syscall_0(Syscall::Shutdown);
+    }
+    unreachable!("shutdown returned")
+}
+
+// Restarts the machine
+pub fn reboot() -> ! {
+    unsafe {
+        let _ = syscall_0(Syscall::Reboot);
+    }
+    unreachable!("reboot returned")
 }